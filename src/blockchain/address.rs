@@ -0,0 +1,252 @@
+use std::{fmt, str::FromStr, sync::OnceLock};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+
+use crate::utils::HashHex;
+
+/// Size of a public key hash, see `script::hash160`
+const PUB_KEY_HASH_SIZE: usize = 20;
+const CHECKSUM_SIZE: usize = 4;
+const ADDRESS_SIZE: usize = 1 + PUB_KEY_HASH_SIZE + CHECKSUM_SIZE;
+
+/// Two bytes version of the addresses written before there were networks
+const LEGACY_VERSION: [u8; 2] = [1, 0];
+
+static NETWORK: OnceLock<Network> = OnceLock::new();
+
+/// Network of the node, every network has its own address version so
+/// addresses of one are refused by the others
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    #[default]
+    Mainnet,
+    Testnet,
+    Regtest,
+}
+
+impl Network {
+    pub fn version(&self) -> u8 {
+        match self {
+            Network::Mainnet => 0x00,
+            Network::Testnet => 0x6f,
+            Network::Regtest => 0x7a,
+        }
+    }
+
+    fn from_version(version: u8) -> Option<Self> {
+        [Network::Mainnet, Network::Testnet, Network::Regtest]
+            .into_iter()
+            .find(|network| network.version() == version)
+    }
+
+    /// Network the node runs on, `Mainnet` unless another one is set on start
+    pub fn current() -> Network {
+        NETWORK.get().copied().unwrap_or_default()
+    }
+
+    /// Picks the network once, before any address is used
+    pub fn set(network: Network) {
+        NETWORK.set(network).expect("Network is set already");
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UnknownNetworkError;
+
+impl fmt::Display for UnknownNetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Network has to be mainnet, testnet or regtest")
+    }
+}
+
+impl std::error::Error for UnknownNetworkError {}
+
+impl FromStr for Network {
+    type Err = UnknownNetworkError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "mainnet" => Ok(Network::Mainnet),
+            "testnet" => Ok(Network::Testnet),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(UnknownNetworkError),
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Network::Mainnet => write!(f, "mainnet"),
+            Network::Testnet => write!(f, "testnet"),
+            Network::Regtest => write!(f, "regtest"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddressError {
+    Hex,
+    Base58,
+    Length(usize),
+    Checksum,
+    UnknownVersion(u8),
+    WrongNetwork(Network),
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressError::Hex => write!(f, "Address is not hex encoded"),
+            AddressError::Base58 => write!(f, "Address is not valid base58"),
+            AddressError::Length(length) => write!(
+                f,
+                "Address has {} bytes instead of {}",
+                length, ADDRESS_SIZE
+            ),
+            AddressError::Checksum => {
+                write!(f, "Address checksum doesn't match, it is probably mistyped")
+            }
+            AddressError::UnknownVersion(version) => {
+                write!(f, "Address version {:#04x} is unknown", version)
+            }
+            AddressError::WrongNetwork(network) => write!(
+                f,
+                "Address belongs to {}, this node runs on {}",
+                network,
+                Network::current()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AddressError {}
+
+/// First 4 bytes of the double SHA-256 of `payload`
+pub fn checksum(payload: &[u8]) -> Vec<u8> {
+    Sha256::digest(Sha256::digest(payload))[..CHECKSUM_SIZE].to_vec()
+}
+
+/// Public key hash along with the network it is meant for. Written as base58 of
+/// the network version, the hash and a checksum; the API takes and returns the
+/// hex of that base58 string.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Address {
+    network: Network,
+    pub_key_hash: HashHex,
+}
+
+impl Address {
+    /// Address of `pub_key_hash` on the current network
+    pub fn new(pub_key_hash: HashHex) -> Self {
+        Address {
+            network: Network::current(),
+            pub_key_hash,
+        }
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    pub fn pub_key_hash(&self) -> &HashHex {
+        &self.pub_key_hash
+    }
+
+    pub fn to_base58(&self) -> String {
+        let mut payload = vec![self.network.version()];
+        payload.extend(self.pub_key_hash.to_vec());
+        payload.extend(checksum(&payload));
+
+        bs58::encode(payload).into_string()
+    }
+
+    /// Checks the checksum first, so a mistyped address is never mistaken for
+    /// one of another network
+    pub fn from_base58(s: &str) -> Result<Self, AddressError> {
+        let bytes = bs58::decode(s)
+            .into_vec()
+            .map_err(|_| AddressError::Base58)?;
+
+        if bytes.len() != ADDRESS_SIZE {
+            return Err(AddressError::Length(bytes.len()));
+        }
+
+        let (payload, sum) = bytes.split_at(1 + PUB_KEY_HASH_SIZE);
+        if checksum(payload) != sum {
+            return Err(AddressError::Checksum);
+        }
+
+        let network =
+            Network::from_version(payload[0]).ok_or(AddressError::UnknownVersion(payload[0]))?;
+        if network != Network::current() {
+            return Err(AddressError::WrongNetwork(network));
+        }
+
+        Ok(Address {
+            network,
+            pub_key_hash: payload[1..].into(),
+        })
+    }
+
+    /// Bytes of the base58 string, which local wallets are keyed by in the store
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_base58().into_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, AddressError> {
+        let s = std::str::from_utf8(bytes).map_err(|_| AddressError::Base58)?;
+
+        Self::from_base58(s)
+    }
+
+    /// Address on the current network of one written before there were network
+    /// versions, `None` for any other format
+    pub fn migrate(bytes: &[u8]) -> Option<Self> {
+        let bytes = bs58::decode(bytes).into_vec().ok()?;
+
+        // Legacy addresses have a two bytes version
+        if bytes.len() != ADDRESS_SIZE + 1 || bytes[..2] != LEGACY_VERSION {
+            return None;
+        }
+
+        let (payload, sum) = bytes.split_at(bytes.len() - CHECKSUM_SIZE);
+        if checksum(payload) != sum {
+            return None;
+        }
+
+        Some(Address::new(payload[2..].into()))
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.to_bytes()))
+    }
+}
+
+impl FromStr for Address {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let bytes = hex::decode(s.trim()).map_err(|_| AddressError::Hex)?;
+
+        Self::from_bytes(&bytes)
+    }
+}
+
+impl Serialize for Address {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+
+        Address::from_str(&s).map_err(de::Error::custom)
+    }
+}
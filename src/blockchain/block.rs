@@ -1,77 +1,282 @@
-use serde::{Deserialize, Serialize};
+use num_bigint::BigUint;
+use serde::{de, Deserialize, Deserializer, Serialize};
 use serde_with::serde_as;
+use sha2::{Digest, Sha256};
 
 use crate::{
     store::AppStore,
     utils::{get_current_time, HashHex, Result},
 };
 
-use super::{proof_of_work::ProofOfWork, transaction::Transaction, merkle_tree::MerkleTree};
+use super::{
+    address::Address,
+    chain_params::CHAIN_PARAMS,
+    merkle_tree::MerkleTree,
+    miner::Miner,
+    proof_of_work::{MiningStats, PowError, ProofOfWork},
+    transaction::Transaction,
+};
+
+pub const BLOCK_VERSION: u32 = 1;
+
+/// Size of the canonical header encoding:
+/// version (4) | prev_hash (32) | merkle_root (32) | timestamp (8) | bits (4) | nonce (8)
+pub const HEADER_SIZE: usize = 88;
+
+const HASH_SIZE: usize = 32;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BlockHeader {
+    pub version: u32,
+    /// Empty for the genesis block
+    #[serde(deserialize_with = "deserialize_prev_hash")]
+    pub prev_hash: HashHex,
+    #[serde(deserialize_with = "deserialize_hash")]
+    pub merkle_root: HashHex,
+    /// Milliseconds since UNIX epoch
+    pub timestamp: u64,
+    pub bits: u32,
+    pub nonce: u64,
+}
 
 #[serde_as]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Block {
-    pub timestamp: String,
-    pub transactions: Vec<Transaction>,
+    pub header: BlockHeader,
     pub hash: HashHex,
-    pub prev_hash: HashHex,
-    pub nonce: u64,
+    pub height: u64,
+    pub transactions: Vec<Transaction>,
 }
 
-impl Block {
-    pub fn new(prev_hash: HashHex, transactions: Vec<Transaction>) -> Self {
-        let mut new_block: Block = Block {
-            prev_hash,
-            transactions,
-            timestamp: get_current_time(),
-            hash: HashHex(vec![]),
-            nonce: 0,
-        };
+/// Header as it is kept in the headers bucket, along with its position in the chain
+/// and the total work of the branch ending with it
+#[derive(Debug, Clone)]
+pub struct BlockIndex {
+    pub header: BlockHeader,
+    pub height: u64,
+    pub chain_work: BigUint,
+}
 
-        let proof_of_work = ProofOfWork::new(&new_block);
+/// Hashes of received headers have to fit the fixed-size encoding as they are,
+/// so they are never cut or padded into another header
+fn deserialize_hash<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<HashHex, D::Error> {
+    let hash = HashHex::deserialize(deserializer)?;
 
-        let (nonce, hash) = match proof_of_work.run() {
-            Ok(v) => v,
-            Err(e) => panic!("{:?}", e),
+    if hash.0.len() != HASH_SIZE {
+        return Err(de::Error::invalid_length(hash.0.len(), &"a 32 bytes hash"));
+    }
+
+    Ok(hash)
+}
+
+fn deserialize_prev_hash<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<HashHex, D::Error> {
+    let hash = HashHex::deserialize(deserializer)?;
+
+    if !hash.0.is_empty() && hash.0.len() != HASH_SIZE {
+        return Err(de::Error::invalid_length(
+            hash.0.len(),
+            &"a 32 bytes hash or an empty one",
+        ));
+    }
+
+    Ok(hash)
+}
+
+impl BlockHeader {
+    /// Little-endian fixed-size encoding which block hashes are computed from.
+    /// The empty `prev_hash` of the genesis block is encoded as zero bytes.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(HEADER_SIZE);
+
+        data.extend_from_slice(&self.version.to_le_bytes());
+        data.extend_from_slice(&Self::fixed_hash(&self.prev_hash));
+        data.extend_from_slice(&Self::fixed_hash(&self.merkle_root));
+        data.extend_from_slice(&self.timestamp.to_le_bytes());
+        data.extend_from_slice(&self.bits.to_le_bytes());
+        data.extend_from_slice(&self.nonce.to_le_bytes());
+
+        data
+    }
+
+    pub fn deserialize(data: &[u8]) -> Result<Self> {
+        if data.len() != HEADER_SIZE {
+            return Err(format!("Block header must be {} bytes long", HEADER_SIZE).into());
+        }
+
+        let (version, data) = data.split_at(4);
+        let (prev_hash, data) = data.split_at(HASH_SIZE);
+        let (merkle_root, data) = data.split_at(HASH_SIZE);
+        let (timestamp, data) = data.split_at(8);
+        let (bits, nonce) = data.split_at(4);
+
+        let prev_hash = if prev_hash.iter().all(|b| *b == 0) {
+            HashHex(vec![])
+        } else {
+            prev_hash.into()
         };
 
-        new_block.hash = hash;
-        new_block.nonce = nonce;
+        Ok(BlockHeader {
+            version: u32::from_le_bytes(version.try_into()?),
+            prev_hash,
+            merkle_root: merkle_root.into(),
+            timestamp: u64::from_le_bytes(timestamp.try_into()?),
+            bits: u32::from_le_bytes(bits.try_into()?),
+            nonce: u64::from_le_bytes(nonce.try_into()?),
+        })
+    }
+
+    pub fn hash(&self) -> HashHex {
+        let hash_bytes: [u8; 32] = Sha256::digest(self.serialize()).into();
+
+        HashHex(hash_bytes.to_vec())
+    }
+
+    /// Hashes are 32 bytes long, or empty for the genesis `prev_hash`; received
+    /// headers are checked for it when they are deserialized
+    fn fixed_hash(hash: &HashHex) -> [u8; HASH_SIZE] {
+        let mut bytes = [0_u8; HASH_SIZE];
+        bytes[..hash.0.len()].copy_from_slice(&hash.0);
+
+        bytes
+    }
+}
+
+impl Block {
+    pub fn new(prev_hash: HashHex, height: u64, transactions: Vec<Transaction>, bits: u32) -> Self {
+        let mut new_block = Block::new_template(prev_hash, height, transactions, bits);
+
+        if let Err(e) = new_block.mine(Miner::available_threads(), &|| false) {
+            panic!("{:?}", e);
+        }
 
         new_block
     }
 
-    pub fn new_genesis(address: String, store: &AppStore) -> Result<Self> {
-        let tx = Transaction::new_coinbase(address, None, store)?;
+    /// Block which is not mined yet, see `Block::mine`
+    pub fn new_template(
+        prev_hash: HashHex,
+        height: u64,
+        transactions: Vec<Transaction>,
+        bits: u32,
+    ) -> Self {
+        // A template always starts with its coinbase
+        let merkle_root =
+            Self::hash_transactions(&transactions).expect("Block template has no transactions");
 
-        Ok(Block::new(HashHex(vec![]), vec![tx]))
+        Block {
+            header: BlockHeader {
+                version: BLOCK_VERSION,
+                prev_hash,
+                merkle_root,
+                timestamp: get_current_time(),
+                bits,
+                nonce: 0,
+            },
+            hash: HashHex(vec![]),
+            height,
+            transactions,
+        }
     }
 
-    pub fn hash_transactions(&self) -> Vec<u8> {
-        let transactions = &self.transactions;
+    pub fn mine(
+        &mut self,
+        threads: usize,
+        is_cancelled: &(dyn Fn() -> bool + Sync),
+    ) -> std::result::Result<MiningStats, PowError> {
+        let proof_of_work = ProofOfWork::new(&self.header);
+
+        let (nonce, hash, stats) = proof_of_work.run(threads, is_cancelled)?;
+
+        self.header.nonce = nonce;
+        self.hash = hash;
+
+        Ok(stats)
+    }
+
+    pub fn new_genesis(address: Address, store: &AppStore) -> Result<Self> {
+        let tx = Transaction::new_coinbase(address, None, 0, 0, store)?;
+
+        Ok(Block::new(
+            HashHex(vec![]),
+            0,
+            vec![tx],
+            CHAIN_PARAMS.genesis_bits,
+        ))
+    }
 
+    /// Merkle root over the full hashes of `transactions` (see `Transaction::full_hash`),
+    /// `None` when there are none
+    pub fn hash_transactions(transactions: &[Transaction]) -> Option<HashHex> {
         let tx_hashes: Vec<Vec<u8>> = transactions
             .iter()
-            .map(|tx| serde_json::to_vec(tx).unwrap())
+            .map(|tx| tx.full_hash().to_vec())
             .collect();
 
-        let merkle_tree = MerkleTree::new(tx_hashes);
+        let merkle_tree = MerkleTree::new(tx_hashes)?;
 
-        merkle_tree.root.data
+        Some(HashHex(merkle_tree.root.data))
     }
 }
 
+/// Stored as the canonical header, little-endian height and JSON encoded transactions
 impl From<Block> for kv::Raw {
     fn from(block: Block) -> Self {
-        let temp = serde_json::to_string(&block).expect("Block to kv::Raw error");
-        let raw_data = temp.as_bytes();
+        let mut raw_data = block.header.serialize();
+        raw_data.extend_from_slice(&block.height.to_le_bytes());
+        raw_data.extend(serde_json::to_vec(&block.transactions).expect("Block to kv::Raw error"));
+
         kv::Raw::from(raw_data)
     }
 }
 
 impl From<kv::Raw> for Block {
     fn from(raw: kv::Raw) -> Self {
-        let json = String::from_utf8_lossy(&raw);
-        serde_json::from_str(&json).expect("kv::Raw to Block error")
+        let (header, data) = raw.split_at(HEADER_SIZE);
+        let (height, transactions) = data.split_at(8);
+
+        let header = BlockHeader::deserialize(header).expect("kv::Raw to BlockHeader error");
+        let height = u64::from_le_bytes(height.try_into().expect("kv::Raw to height error"));
+        let transactions = serde_json::from_slice(transactions).expect("kv::Raw to Block error");
+
+        Block {
+            hash: header.hash(),
+            header,
+            height,
+            transactions,
+        }
+    }
+}
+
+impl BlockIndex {
+    pub fn hash(&self) -> HashHex {
+        self.header.hash()
+    }
+}
+
+/// Stored as the canonical header, little-endian height and big-endian chain work
+impl From<BlockIndex> for kv::Raw {
+    fn from(index: BlockIndex) -> Self {
+        let mut raw_data = index.header.serialize();
+        raw_data.extend_from_slice(&index.height.to_le_bytes());
+        raw_data.extend(index.chain_work.to_bytes_be());
+
+        kv::Raw::from(raw_data)
+    }
+}
+
+impl From<kv::Raw> for BlockIndex {
+    fn from(raw: kv::Raw) -> Self {
+        let (header, data) = raw.split_at(HEADER_SIZE);
+        let (height, chain_work) = data.split_at(8);
+
+        BlockIndex {
+            header: BlockHeader::deserialize(header).expect("kv::Raw to BlockHeader error"),
+            height: u64::from_le_bytes(height.try_into().expect("kv::Raw to height error")),
+            chain_work: BigUint::from_bytes_be(chain_work),
+        }
     }
 }
@@ -0,0 +1,86 @@
+/// Consensus constants every node of the chain has to agree on
+pub struct ChainParams {
+    /// Compact difficulty target of the genesis block
+    pub genesis_bits: u32,
+    /// Easiest compact target which retargeting is allowed to reach
+    pub pow_limit_bits: u32,
+    /// Difficulty is recalculated once per this amount of blocks
+    pub retarget_interval: u64,
+    /// Desired time between two blocks, in milliseconds
+    pub target_block_time: u64,
+    /// Amount of previous blocks whose median timestamp a new block must exceed
+    pub median_time_span: usize,
+    /// How far a block timestamp may be ahead of the local clock, in milliseconds
+    pub max_future_block_time: u64,
+    /// Coinbase subsidy of the first era
+    pub initial_subsidy: u32,
+    /// Subsidy is halved once per this amount of blocks
+    pub halving_interval: u64,
+    /// Subsidies stop once this many coins are issued
+    pub max_supply: Option<u64>,
+    /// Most bytes an `OP_RETURN` output may carry
+    pub max_data_size: usize,
+}
+
+pub const CHAIN_PARAMS: ChainParams = ChainParams {
+    // 2^238, the same target as the former constant of 18 leading zero bits
+    genesis_bits: 0x1e40_0000,
+    // 2^248
+    pow_limit_bits: 0x2001_0000,
+    retarget_interval: 10,
+    target_block_time: 10_000,
+    median_time_span: 11,
+    max_future_block_time: 2 * 60 * 60 * 1000,
+    initial_subsidy: 10,
+    halving_interval: 100,
+    max_supply: Some(1_250),
+    max_data_size: 80,
+};
+
+impl ChainParams {
+    /// Subsidy of the block at `height`, fees are not included
+    pub fn block_subsidy(&self, height: u64) -> u32 {
+        let subsidy = self.scheduled_subsidy(height);
+
+        match self.max_supply {
+            Some(max_supply) => {
+                let left = max_supply.saturating_sub(self.issued_supply(height));
+
+                subsidy.min(left.try_into().unwrap_or(u32::MAX))
+            }
+            None => subsidy,
+        }
+    }
+
+    /// Coins issued by the subsidies of all blocks below `height`
+    pub fn issued_supply(&self, height: u64) -> u64 {
+        let mut issued = 0_u64;
+        let mut era_start = 0;
+
+        while era_start < height {
+            let subsidy = self.scheduled_subsidy(era_start);
+            if subsidy == 0 {
+                break;
+            }
+
+            let era_end = (era_start + self.halving_interval).min(height);
+            issued += subsidy as u64 * (era_end - era_start);
+            era_start = era_end;
+        }
+
+        match self.max_supply {
+            Some(max_supply) => issued.min(max_supply),
+            None => issued,
+        }
+    }
+
+    fn scheduled_subsidy(&self, height: u64) -> u32 {
+        let halvings = height / self.halving_interval;
+
+        if halvings >= u32::BITS as u64 {
+            return 0;
+        }
+
+        self.initial_subsidy >> halvings
+    }
+}
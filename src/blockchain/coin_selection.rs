@@ -0,0 +1,149 @@
+use std::cmp::Reverse;
+
+use rand::seq::SliceRandom;
+use serde::Deserialize;
+
+use super::utxo_set::Coin;
+
+/// Branch and bound gives up after so many visited branches
+const MAX_TRIES: usize = 100_000;
+
+/// Picks coins which cover `target`, `None` when all of them are not enough
+pub trait CoinSelector {
+    fn select(&self, coins: &[Coin], target: u64) -> Option<Vec<Coin>>;
+}
+
+/// Takes coins in the given order until `target` is reached
+fn accumulate(coins: impl IntoIterator<Item = Coin>, target: u64) -> Option<Vec<Coin>> {
+    let mut selected = Vec::new();
+    let mut acc = 0u64;
+
+    for coin in coins {
+        if acc >= target {
+            break;
+        }
+
+        acc += coin.value as u64;
+        selected.push(coin);
+    }
+
+    (acc >= target).then_some(selected)
+}
+
+/// Spends few big coins, keeps the transaction small
+pub struct LargestFirst;
+
+impl CoinSelector for LargestFirst {
+    fn select(&self, coins: &[Coin], target: u64) -> Option<Vec<Coin>> {
+        let mut coins = coins.to_vec();
+        coins.sort_by_key(|coin| Reverse(coin.value));
+
+        accumulate(coins, target)
+    }
+}
+
+/// Consolidates dust by spending small coins first
+pub struct SmallestFirst;
+
+impl CoinSelector for SmallestFirst {
+    fn select(&self, coins: &[Coin], target: u64) -> Option<Vec<Coin>> {
+        let mut coins = coins.to_vec();
+        coins.sort_by_key(|coin| coin.value);
+
+        accumulate(coins, target)
+    }
+}
+
+/// Searches coins which sum up to `target` exactly, so no change output is needed.
+/// Falls back to `LargestFirst` when there is no such set.
+pub struct BranchAndBound;
+
+impl BranchAndBound {
+    fn search(
+        coins: &[Coin],
+        remaining: &[u64],
+        index: usize,
+        target: u64,
+        selected: &mut Vec<usize>,
+        tries: &mut usize,
+    ) -> bool {
+        if target == 0 {
+            return true;
+        }
+
+        *tries += 1;
+        if index == coins.len() || remaining[index] < target || *tries > MAX_TRIES {
+            return false;
+        }
+
+        let value = coins[index].value as u64;
+
+        if value <= target {
+            selected.push(index);
+
+            if Self::search(coins, remaining, index + 1, target - value, selected, tries) {
+                return true;
+            }
+
+            selected.pop();
+        }
+
+        Self::search(coins, remaining, index + 1, target, selected, tries)
+    }
+}
+
+impl CoinSelector for BranchAndBound {
+    fn select(&self, coins: &[Coin], target: u64) -> Option<Vec<Coin>> {
+        let mut sorted = coins.to_vec();
+        sorted.sort_by_key(|coin| Reverse(coin.value));
+
+        // Sum of the coins from every index to the end bounds the search
+        let mut remaining = vec![0u64; sorted.len() + 1];
+        for (index, coin) in sorted.iter().enumerate().rev() {
+            remaining[index] = remaining[index + 1] + coin.value as u64;
+        }
+
+        let mut selected = Vec::new();
+        let mut tries = 0;
+
+        if Self::search(&sorted, &remaining, 0, target, &mut selected, &mut tries) {
+            return Some(selected.into_iter().map(|i| sorted[i].clone()).collect());
+        }
+
+        LargestFirst.select(coins, target)
+    }
+}
+
+/// Takes coins in random order, so spends don't reveal which coins a wallet holds
+pub struct RandomSelection;
+
+impl CoinSelector for RandomSelection {
+    fn select(&self, coins: &[Coin], target: u64) -> Option<Vec<Coin>> {
+        let mut coins = coins.to_vec();
+        coins.shuffle(&mut rand::thread_rng());
+
+        accumulate(coins, target)
+    }
+}
+
+/// Coin selection strategy of a request
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum CoinSelection {
+    #[default]
+    LargestFirst,
+    SmallestFirst,
+    BranchAndBound,
+    Random,
+}
+
+impl CoinSelection {
+    pub fn selector(&self) -> Box<dyn CoinSelector> {
+        match self {
+            CoinSelection::LargestFirst => Box::new(LargestFirst),
+            CoinSelection::SmallestFirst => Box::new(SmallestFirst),
+            CoinSelection::BranchAndBound => Box::new(BranchAndBound),
+            CoinSelection::Random => Box::new(RandomSelection),
+        }
+    }
+}
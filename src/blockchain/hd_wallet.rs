@@ -0,0 +1,311 @@
+use std::{collections::HashSet, fmt, str::FromStr};
+
+use bip39::{Language, Mnemonic};
+use hmac::{Hmac, Mac};
+use p256::{
+    ecdsa::{SigningKey, VerifyingKey},
+    elliptic_curve::ff::{Field, PrimeField},
+    FieldBytes, Scalar,
+};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Sha512;
+
+use crate::{
+    store::AppStore,
+    utils::{HashHex, Result},
+};
+
+use super::{keystore::Keystore, wallet::Wallet, Blockchain};
+
+/// Indexes from this one on are hardened, written as `i'` in paths
+pub const HARDENED: u32 = 1 << 31;
+
+/// Receiving addresses of the first account
+pub const DEFAULT_ACCOUNT_PATH: &str = "m/44'/1'/0'/0";
+
+/// Unused addresses in a row after which a restore stops scanning
+pub const DEFAULT_GAP_LIMIT: u32 = 20;
+
+const MNEMONIC_WORDS: usize = 12;
+
+/// HMAC key of the master key derivation for P-256 (SLIP-0010)
+const MASTER_KEY_SALT: &[u8] = b"Nist256p1 seed";
+
+#[derive(Debug, Clone)]
+pub struct DerivationPathError;
+
+impl fmt::Display for DerivationPathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Derivation path has to look like m/44'/1'/0'/0")
+    }
+}
+
+impl std::error::Error for DerivationPathError {}
+
+/// Child indexes from the master key, e.g. `m/44'/1'/0'/0`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DerivationPath(pub Vec<u32>);
+
+impl FromStr for DerivationPath {
+    type Err = DerivationPathError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut parts = s.split('/');
+
+        if parts.next() != Some("m") {
+            return Err(DerivationPathError);
+        }
+
+        let path = parts
+            .map(|part| {
+                let (number, hardened) = match part.strip_suffix('\'') {
+                    Some(number) => (number, true),
+                    None => (part, false),
+                };
+
+                match number.parse::<u32>() {
+                    Ok(index) if index < HARDENED => {
+                        Ok(if hardened { index | HARDENED } else { index })
+                    }
+                    _ => Err(DerivationPathError),
+                }
+            })
+            .collect::<std::result::Result<Vec<u32>, _>>()?;
+
+        Ok(DerivationPath(path))
+    }
+}
+
+impl fmt::Display for DerivationPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "m")?;
+
+        for index in self.0.iter() {
+            if index & HARDENED != 0 {
+                write!(f, "/{}'", index & !HARDENED)?;
+            } else {
+                write!(f, "/{}", index)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Private key along with the chain code its children are derived with (SLIP-0010)
+#[derive(Clone)]
+pub struct ExtendedKey {
+    pub private_key: SigningKey,
+    chain_code: [u8; 32],
+}
+
+impl ExtendedKey {
+    pub fn master(seed: &[u8]) -> Result<Self> {
+        let mut data = seed.to_vec();
+
+        loop {
+            let (key, chain_code) = Self::hmac(MASTER_KEY_SALT, &data)?;
+
+            // Almost never happens, the next candidate is derived from the rejected one
+            match Self::parse_scalar(&key) {
+                Some(scalar) if !bool::from(scalar.is_zero()) => {
+                    return Ok(ExtendedKey {
+                        private_key: SigningKey::from_bytes(&key)?,
+                        chain_code,
+                    });
+                }
+                _ => data = [key, chain_code].concat(),
+            }
+        }
+    }
+
+    pub fn derive_child(&self, index: u32) -> Result<Self> {
+        let parent = Self::parse_scalar(&self.private_key.to_bytes()).ok_or("Bad parent key")?;
+
+        let mut data = if index & HARDENED != 0 {
+            [&[0u8][..], &self.private_key.to_bytes()].concat()
+        } else {
+            let public_key = VerifyingKey::from(&self.private_key);
+            public_key.to_encoded_point(true).as_bytes().to_vec()
+        };
+        data.extend(index.to_be_bytes());
+
+        loop {
+            let (tweak, chain_code) = Self::hmac(&self.chain_code, &data)?;
+
+            if let Some(tweak) = Self::parse_scalar(&tweak) {
+                let child = parent + tweak;
+
+                if !bool::from(child.is_zero()) {
+                    return Ok(ExtendedKey {
+                        private_key: SigningKey::from_bytes(&child.to_bytes())?,
+                        chain_code,
+                    });
+                }
+            }
+
+            data = [&[1u8][..], &chain_code, &index.to_be_bytes()].concat();
+        }
+    }
+
+    pub fn derive_path(&self, path: &DerivationPath) -> Result<Self> {
+        path.0
+            .iter()
+            .try_fold(self.clone(), |key, index| key.derive_child(*index))
+    }
+
+    fn hmac(key: &[u8], data: &[u8]) -> Result<([u8; 32], [u8; 32])> {
+        let mut mac = Hmac::<Sha512>::new_from_slice(key)?;
+        mac.update(data);
+        let result = mac.finalize().into_bytes();
+
+        let mut left = [0u8; 32];
+        let mut right = [0u8; 32];
+        left.copy_from_slice(&result[..32]);
+        right.copy_from_slice(&result[32..]);
+
+        Ok((left, right))
+    }
+
+    /// `None` when `bytes` are not lower than the curve order
+    fn parse_scalar(bytes: &[u8]) -> Option<Scalar> {
+        Scalar::from_repr(*FieldBytes::from_slice(bytes)).into()
+    }
+}
+
+/// Mnemonic backup of all wallets derived along `account_path`, `next_index` is
+/// the first one which is not handed out yet
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HdKeychain {
+    pub mnemonic: String,
+    pub account_path: String,
+    pub next_index: u32,
+}
+
+impl HdKeychain {
+    pub fn generate(account_path: &str) -> Result<Self> {
+        let mut entropy = vec![0u8; MNEMONIC_WORDS / 3 * 4];
+        OsRng.fill_bytes(&mut entropy);
+
+        let mnemonic = Mnemonic::from_entropy_in(Language::English, &entropy)?;
+
+        Self::from_mnemonic(&mnemonic.to_string(), account_path)
+    }
+
+    pub fn from_mnemonic(mnemonic: &str, account_path: &str) -> Result<Self> {
+        let mnemonic = Mnemonic::parse_in(Language::English, mnemonic)?;
+        let account_path = DerivationPath::from_str(account_path)?;
+
+        Ok(HdKeychain {
+            mnemonic: mnemonic.to_string(),
+            account_path: account_path.to_string(),
+            next_index: 0,
+        })
+    }
+
+    pub const KEY: &'static [u8] = b"keychain";
+
+    pub fn exists(store: &AppStore) -> Result<bool> {
+        Ok(Self::load_sealed(store)?.is_some())
+    }
+
+    pub fn load(store: &AppStore) -> Result<Option<Self>> {
+        let mut keychain = match Self::load_sealed(store)? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+
+        if Keystore::is_encrypted(store)? {
+            let mnemonic = Keystore::open(store, &hex::decode(&keychain.mnemonic)?)?;
+            keychain.mnemonic = String::from_utf8(mnemonic)?;
+        }
+
+        Ok(Some(keychain))
+    }
+
+    /// Keychain as it is stored, its mnemonic is hex encoded ciphertext once the
+    /// wallet has a passphrase
+    pub fn load_sealed(store: &AppStore) -> Result<Option<Self>> {
+        let bucket = store.get_hd_wallet_bucket()?;
+
+        Ok(bucket.get(Self::KEY)?.map(|keychain| keychain.0))
+    }
+
+    pub fn save(&self, store: &AppStore) -> Result<()> {
+        let mut keychain = self.clone();
+
+        if Keystore::is_encrypted(store)? {
+            keychain.mnemonic = hex::encode(Keystore::seal(store, self.mnemonic.as_bytes())?);
+        }
+
+        let bucket = store.get_hd_wallet_bucket()?;
+        bucket.set(Self::KEY, kv::Json(keychain))?;
+
+        Ok(())
+    }
+
+    /// Key at `account_path`, its children are the wallets. The mnemonic seed goes
+    /// through PBKDF2, so the key is derived once for all wallets being derived.
+    pub fn account_key(&self) -> Result<ExtendedKey> {
+        let mnemonic = Mnemonic::parse_in(Language::English, self.mnemonic.as_str())?;
+        let master = ExtendedKey::master(&mnemonic.to_seed(""))?;
+
+        master.derive_path(&DerivationPath::from_str(&self.account_path)?)
+    }
+
+    /// Wallet at `index` of the account with `account_key`
+    pub fn derive_wallet(account_key: &ExtendedKey, index: u32) -> Result<Wallet> {
+        let private_key = account_key.derive_child(index)?.private_key;
+
+        Ok(Wallet {
+            public_key: VerifyingKey::from(&private_key),
+            private_key,
+        })
+    }
+
+    /// Hands out the next unused wallet
+    pub fn next_wallet(&mut self) -> Result<Wallet> {
+        let wallet = Self::derive_wallet(&self.account_key()?, self.next_index)?;
+        self.next_index += 1;
+
+        Ok(wallet)
+    }
+
+    /// Derives wallets until `gap_limit` of them in a row have never received coins,
+    /// moves `next_index` after the last used one and returns all wallets before it
+    pub fn scan(&mut self, used: &HashSet<HashHex>, gap_limit: u32) -> Result<Vec<Wallet>> {
+        let account_key = self.account_key()?;
+
+        let mut derived = Vec::new();
+        let mut gap = 0;
+
+        while gap < gap_limit {
+            let wallet = Self::derive_wallet(&account_key, derived.len() as u32)?;
+            let pub_key_hash = Wallet::hash_pub_key(wallet.pub_key_bytes_vec());
+
+            derived.push(wallet);
+
+            if used.contains(&pub_key_hash) {
+                self.next_index = derived.len() as u32;
+                gap = 0;
+            } else {
+                gap += 1;
+            }
+        }
+
+        derived.truncate(self.next_index as usize);
+
+        Ok(derived)
+    }
+
+    /// Public key hashes which p2pkh outputs of the main chain were ever locked to
+    pub fn used_pub_key_hashes(blockchain: &Blockchain) -> HashSet<HashHex> {
+        blockchain
+            .to_owned()
+            .flat_map(|block| block.transactions)
+            .flat_map(|tx| tx.outputs)
+            .filter_map(|output| output.script_pubkey.p2pkh_hash())
+            .collect()
+    }
+}
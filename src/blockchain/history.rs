@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::utils::{HashHex, Result};
+
+use super::Blockchain;
+
+/// Main chain transaction which pays to or spends from an address
+#[derive(Serialize, Debug, Clone)]
+pub struct HistoryEntry {
+    pub tx_id: HashHex,
+    pub height: u64,
+    pub received: u64,
+    pub sent: u64,
+}
+
+pub struct AddressHistory<'a> {
+    pub blockchain: &'a Blockchain<'a>,
+}
+
+impl<'a> AddressHistory<'a> {
+    /// Walks the main chain from genesis, following outputs locked to `pub_key_hash`
+    /// until they are spent
+    pub fn get(&self, pub_key_hash: &HashHex) -> Result<Vec<HistoryEntry>> {
+        let tip_height = self.blockchain.height()?;
+
+        let mut owned = HashMap::<(HashHex, i32), u32>::new();
+        let mut history = Vec::new();
+
+        for block in self.blockchain.get_blocks_range(0, tip_height)? {
+            for tx in block.transactions.iter() {
+                let sent: u64 = tx
+                    .inputs
+                    .iter()
+                    .filter_map(|input| owned.remove(&(input.tx_id.clone(), input.output_index)))
+                    .map(|value| value as u64)
+                    .sum();
+
+                let mut received = 0u64;
+                for (index, output) in tx.outputs.iter().enumerate() {
+                    if output.is_locked_with(pub_key_hash) {
+                        received += output.value as u64;
+                        owned.insert((tx.id.clone(), index as i32), output.value);
+                    }
+                }
+
+                if sent > 0 || received > 0 {
+                    history.push(HistoryEntry {
+                        tx_id: tx.id.clone(),
+                        height: block.height,
+                        received,
+                        sent,
+                    });
+                }
+            }
+        }
+
+        Ok(history)
+    }
+}
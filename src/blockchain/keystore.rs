@@ -0,0 +1,303 @@
+use std::fmt;
+
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use kv::{Batch, Json};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    store::AppStore,
+    utils::{HashHex, Result},
+};
+
+use super::hd_wallet::HdKeychain;
+
+const KEYSTORE_KEY: &[u8] = b"keystore";
+
+/// Keys of a staged passphrase change, wallets go by their address after the prefix
+const PENDING_PARAMS_KEY: &[u8] = b"params";
+const PENDING_KEYCHAIN_KEY: &[u8] = b"keychain";
+const PENDING_WALLET_PREFIX: &[u8] = b"wallet:";
+
+/// Encrypted with the wallet key, so a passphrase can be checked before it is used
+const CHECK_PLAINTEXT: &[u8] = b"keystore";
+
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+
+#[derive(Debug, Clone)]
+pub struct WalletLockedError;
+
+impl fmt::Display for WalletLockedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Wallet is locked, unlock it with its passphrase first")
+    }
+}
+
+impl std::error::Error for WalletLockedError {}
+
+#[derive(Debug, Clone)]
+pub struct WrongPassphraseError;
+
+impl fmt::Display for WrongPassphraseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Passphrase is wrong")
+    }
+}
+
+impl std::error::Error for WrongPassphraseError {}
+
+#[derive(Debug, Clone)]
+pub struct NotEncryptedError;
+
+impl fmt::Display for NotEncryptedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Wallet has no passphrase yet")
+    }
+}
+
+impl std::error::Error for NotEncryptedError {}
+
+/// Kept next to the encrypted wallets, the key itself is never stored
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeystoreParams {
+    pub salt: HashHex,
+    pub check: HashHex,
+}
+
+/// Key derived from the passphrase, held in memory while the wallet is unlocked
+#[derive(Clone)]
+pub struct WalletKey(Key);
+
+impl WalletKey {
+    fn derive(passphrase: &str, salt: &[u8]) -> Result<Self> {
+        let mut key = Key::default();
+
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+            .map_err(|e| e.to_string())?;
+
+        Ok(WalletKey(key))
+    }
+
+    /// Random nonce followed by the ciphertext
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+
+        let ciphertext = ChaCha20Poly1305::new(&self.0)
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .map_err(|_| "Wallet encryption failed")?;
+
+        Ok([nonce.to_vec(), ciphertext].concat())
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        if data.len() < NONCE_SIZE {
+            return Err(WrongPassphraseError.into());
+        }
+
+        let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+
+        let plaintext = ChaCha20Poly1305::new(&self.0)
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| WrongPassphraseError)?;
+
+        Ok(plaintext)
+    }
+}
+
+/// Wallet secrets at rest: plaintext until a passphrase is set, encrypted with
+/// the passphrase derived key afterwards
+pub struct Keystore;
+
+impl Keystore {
+    pub fn params(store: &AppStore) -> Result<Option<KeystoreParams>> {
+        let bucket = store.get_keystore_bucket()?;
+
+        Ok(bucket.get(KEYSTORE_KEY)?.map(|params| params.0))
+    }
+
+    pub fn is_encrypted(store: &AppStore) -> Result<bool> {
+        Ok(Self::params(store)?.is_some())
+    }
+
+    pub fn is_locked(store: &AppStore) -> Result<bool> {
+        Ok(Self::is_encrypted(store)? && store.wallet_key().is_none())
+    }
+
+    pub fn unlock(store: &mut AppStore, passphrase: &str) -> Result<()> {
+        let params = Self::params(store)?.ok_or(NotEncryptedError)?;
+        let key = Self::check_passphrase(&params, passphrase)?;
+
+        store.set_wallet_key(Some(key));
+
+        Ok(())
+    }
+
+    pub fn lock(store: &mut AppStore) {
+        store.set_wallet_key(None);
+    }
+
+    /// Encrypts all wallets with a key of `passphrase`, `old_passphrase` is needed
+    /// once the wallets are encrypted. The wallet is locked afterwards.
+    pub fn change_passphrase(
+        store: &mut AppStore,
+        old_passphrase: Option<&str>,
+        passphrase: &str,
+    ) -> Result<()> {
+        Self::finish_pending_change(store)?;
+
+        let old_key = match Self::params(store)? {
+            Some(params) => {
+                let old_passphrase = old_passphrase.ok_or(WrongPassphraseError)?;
+                Some(Self::check_passphrase(&params, old_passphrase)?)
+            }
+            None => None,
+        };
+
+        let mut salt = vec![0u8; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+
+        let key = WalletKey::derive(passphrase, &salt)?;
+
+        let reencrypt = |data: &[u8]| -> Result<Vec<u8>> {
+            let plaintext = match &old_key {
+                Some(old_key) => old_key.decrypt(data)?,
+                None => data.to_vec(),
+            };
+
+            key.encrypt(&plaintext)
+        };
+
+        // Everything is encrypted and staged in a single batch before the wallets are
+        // touched, so an interrupted change is either lost or finished on the next start
+        let mut staged = Batch::new();
+        for item in store.get_wallets_bucket()?.iter() {
+            let item = item?;
+            let address: Vec<u8> = item.key()?;
+            let private_key: Vec<u8> = item.value()?;
+
+            staged.set(
+                [PENDING_WALLET_PREFIX, &address].concat(),
+                reencrypt(&private_key)?,
+            )?;
+        }
+
+        let keychain = match HdKeychain::load_sealed(store)? {
+            Some(mut keychain) => {
+                let sealed = match old_key {
+                    Some(_) => hex::decode(&keychain.mnemonic)?,
+                    None => keychain.mnemonic.into_bytes(),
+                };
+
+                keychain.mnemonic = hex::encode(reencrypt(&sealed)?);
+                Some(keychain)
+            }
+            None => None,
+        };
+
+        let params = KeystoreParams {
+            salt: salt.into(),
+            check: key.encrypt(CHECK_PLAINTEXT)?.into(),
+        };
+
+        if let Some(keychain) = keychain {
+            staged.set(PENDING_KEYCHAIN_KEY, serde_json::to_vec(&keychain)?)?;
+        }
+        staged.set(PENDING_PARAMS_KEY, serde_json::to_vec(&params)?)?;
+
+        let pending = store.get_keystore_pending_bucket()?;
+        pending.batch(staged)?;
+        pending.flush()?;
+
+        Self::finish_pending_change(store)?;
+
+        store.set_wallet_key(None);
+
+        Ok(())
+    }
+
+    /// Writes the staged secrets of a passphrase change over the stored ones. Runs on
+    /// start as well, returns whether there was a change to finish.
+    pub fn finish_pending_change(store: &AppStore) -> Result<bool> {
+        let pending = store.get_keystore_pending_bucket()?;
+
+        let params: KeystoreParams = match pending.get(PENDING_PARAMS_KEY)? {
+            Some(params) => serde_json::from_slice(&params)?,
+            None => return Ok(false),
+        };
+
+        let mut wallets = Batch::new();
+        let mut finished = Batch::new();
+        for item in pending.iter_prefix(PENDING_WALLET_PREFIX) {
+            let item = item?;
+            let key: Vec<u8> = item.key()?;
+            let private_key: Vec<u8> = item.value()?;
+
+            wallets.set(&key[PENDING_WALLET_PREFIX.len()..], private_key)?;
+            finished.remove(key)?;
+        }
+        store.get_wallets_bucket()?.batch(wallets)?;
+
+        if let Some(keychain) = pending.get(PENDING_KEYCHAIN_KEY)? {
+            let keychain: HdKeychain = serde_json::from_slice(&keychain)?;
+
+            store
+                .get_hd_wallet_bucket()?
+                .set(HdKeychain::KEY, Json(keychain))?;
+            finished.remove(PENDING_KEYCHAIN_KEY)?;
+        }
+
+        store
+            .get_keystore_bucket()?
+            .set(KEYSTORE_KEY, Json(params))?;
+
+        // Staged secrets go away at once, a half cleared stage is never replayed
+        finished.remove(PENDING_PARAMS_KEY)?;
+        pending.batch(finished)?;
+
+        Ok(true)
+    }
+
+    /// Encrypts a secret before it is stored, as is while there is no passphrase
+    pub fn seal(store: &AppStore, secret: &[u8]) -> Result<Vec<u8>> {
+        match Self::current_key(store)? {
+            Some(key) => key.encrypt(secret),
+            None => Ok(secret.to_vec()),
+        }
+    }
+
+    /// Decrypts a stored secret
+    pub fn open(store: &AppStore, data: &[u8]) -> Result<Vec<u8>> {
+        match Self::current_key(store)? {
+            Some(key) => key.decrypt(data),
+            None => Ok(data.to_vec()),
+        }
+    }
+
+    /// `None` while there is no passphrase, fails while the wallet is locked
+    fn current_key(store: &AppStore) -> Result<Option<&WalletKey>> {
+        if !Self::is_encrypted(store)? {
+            return Ok(None);
+        }
+
+        match store.wallet_key() {
+            Some(key) => Ok(Some(key)),
+            None => Err(WalletLockedError.into()),
+        }
+    }
+
+    fn check_passphrase(params: &KeystoreParams, passphrase: &str) -> Result<WalletKey> {
+        let key = WalletKey::derive(passphrase, &params.salt.0)?;
+
+        match key.decrypt(&params.check.0) {
+            Ok(check) if check == CHECK_PLAINTEXT => Ok(key),
+            _ => Err(WrongPassphraseError.into()),
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use std::{collections::HashSet, fmt};
+
+use kv::Json;
+
+use crate::utils::{HashHex, Result};
+
+use super::{
+    block::Block, reorg::ChainUpdate, transaction::Transaction, utxo_set::UTXOSet,
+    validation::BlockValidator, Blockchain,
+};
+
+#[derive(Debug, Clone)]
+pub enum MempoolError {
+    AlreadyQueued,
+    DoubleSpend,
+}
+
+impl fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MempoolError::AlreadyQueued => write!(f, "Transaction is already in the memory pool"),
+            MempoolError::DoubleSpend => {
+                write!(
+                    f,
+                    "Transaction spends an output used by a queued transaction"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for MempoolError {}
+
+pub type Outpoint = (HashHex, i32);
+
+pub struct Mempool<'a> {
+    pub blockchain: &'a Blockchain<'a>,
+}
+
+impl<'a> Mempool<'a> {
+    pub fn add(&self, tx: Transaction) -> Result<()> {
+        BlockValidator::check_id(&tx)?;
+
+        let bucket = self.blockchain.store.get_mempool_bucket()?;
+
+        if bucket.contains(tx.id.to_vec())? {
+            return Err(MempoolError::AlreadyQueued.into());
+        }
+
+        let spent = self.spent_outputs()?;
+        if tx
+            .inputs
+            .iter()
+            .any(|input| spent.contains(&(input.tx_id.clone(), input.output_index)))
+        {
+            return Err(MempoolError::DoubleSpend.into());
+        }
+
+        let validator = BlockValidator {
+            blockchain: self.blockchain,
+        };
+        let context = validator.next_block_context()?;
+        validator.check_transaction(&tx, &mut HashSet::new(), &context)?;
+
+        bucket.set(tx.id.to_vec(), Json(tx.clone()))?;
+
+        println!("[!] Mempool: transaction {} queued", hex::encode(&tx.id.0));
+
+        Ok(())
+    }
+
+    pub fn get(&self, tx_id: &HashHex) -> Result<Option<Transaction>> {
+        let bucket = self.blockchain.store.get_mempool_bucket()?;
+
+        let tx = bucket.get(tx_id.to_vec())?.map(|v| v.0);
+
+        Ok(tx)
+    }
+
+    pub fn transactions(&self) -> Result<Vec<Transaction>> {
+        let bucket = self.blockchain.store.get_mempool_bucket()?;
+
+        let mut transactions = Vec::new();
+        for item in bucket.iter() {
+            let tx = item?.value::<Json<Transaction>>()?.0;
+            transactions.push(tx);
+        }
+
+        Ok(transactions)
+    }
+
+    /// Transactions which are still valid on top of the current tip, with their total fee
+    pub fn select_transactions(&self) -> Result<(Vec<Transaction>, u64)> {
+        let validator = BlockValidator {
+            blockchain: self.blockchain,
+        };
+
+        let context = validator.next_block_context()?;
+
+        let mut spent = HashSet::new();
+        let mut selected = Vec::new();
+        let mut fees = 0;
+
+        for tx in self.transactions()? {
+            match validator.check_transaction(&tx, &mut spent, &context) {
+                Ok(fee) => {
+                    fees += fee;
+                    selected.push(tx);
+                }
+                Err(e) => println!(
+                    "[!] Mempool: skipping transaction {}: {}",
+                    hex::encode(&tx.id.0),
+                    e
+                ),
+            }
+        }
+
+        Ok((selected, fees))
+    }
+
+    pub fn spent_outputs(&self) -> Result<HashSet<Outpoint>> {
+        let spent = self
+            .transactions()?
+            .iter()
+            .flat_map(|tx| {
+                tx.inputs
+                    .iter()
+                    .map(|input| (input.tx_id.clone(), input.output_index))
+            })
+            .collect();
+
+        Ok(spent)
+    }
+
+    /// Drops transactions confirmed by `block`, then evicts the ones whose
+    /// inputs are no longer unspent after the chainstate update.
+    pub fn remove_confirmed(&self, block: &Block) -> Result<()> {
+        let bucket = self.blockchain.store.get_mempool_bucket()?;
+
+        for tx in block.transactions.iter() {
+            bucket.remove(tx.id.to_vec())?;
+        }
+
+        let utxo_set = UTXOSet {
+            blockchain: self.blockchain,
+        };
+
+        for tx in self.transactions()? {
+            for input in tx.inputs.iter() {
+                if utxo_set
+                    .get_output(&input.tx_id, input.output_index)?
+                    .is_none()
+                {
+                    println!(
+                        "[!] Mempool: evicting stale transaction {}",
+                        hex::encode(&tx.id.0)
+                    );
+                    bucket.remove(tx.id.to_vec())?;
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Follows a chain update: transactions of the disconnected blocks are queued
+    /// again, unless the new branch already confirms or conflicts with them
+    pub fn apply_chain_update(&self, update: &ChainUpdate) -> Result<()> {
+        for block in update.connected.iter() {
+            self.remove_confirmed(block)?;
+        }
+
+        for block in update.disconnected.iter() {
+            for tx in block.transactions.iter().filter(|tx| !tx.is_coinbase()) {
+                if let Err(e) = self.add(tx.clone()) {
+                    println!(
+                        "[!] Mempool: transaction {} is not restored: {}",
+                        hex::encode(&tx.id.0),
+                        e
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
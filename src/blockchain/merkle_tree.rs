@@ -2,6 +2,7 @@ use std::{rc::Rc};
 
 use sha2::{Digest, Sha256};
 
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct MerkleNode {
     pub left: Option<Rc<MerkleNode>>,
@@ -50,11 +51,10 @@ impl MerkleNode {
 
 
 impl MerkleTree {
-    pub fn new(mut data: Vec<Vec<u8>>) -> Self {
-        let data_size = data.len();
-        
-        if data_size % 2 != 0 {
-            data.push(data.last().unwrap().clone());
+    /// `None` when there is no data, as an empty tree has no root
+    pub fn new(data: Vec<Vec<u8>>) -> Option<Self> {
+        if data.is_empty() {
+            return None;
         }
 
         let mut nodes = Vec::<MerkleNode>::new();
@@ -64,11 +64,16 @@ impl MerkleTree {
             nodes.push(node);
         }
 
-        for _i in 0..(data_size/2) {
+        // Every level pairs the nodes up, an odd last node is paired with itself
+        while nodes.len() > 1 {
+            if !nodes.len().is_multiple_of(2) {
+                nodes.push(nodes.last().unwrap().clone());
+            }
+
             let mut next_level = Vec::<MerkleNode>::new();
 
-            for w in nodes.windows(2) {
-                if let [left, right] = w {
+            for pair in nodes.chunks(2) {
+                if let [left, right] = pair {
                     let node = MerkleNode::new(
                         Some(Rc::new(left.to_owned())),
                          Some(Rc::new(right.to_owned())
@@ -81,6 +86,6 @@ impl MerkleTree {
             nodes = next_level;
         }
 
-        MerkleTree { root: nodes[0].clone() }
+        Some(MerkleTree { root: nodes[0].clone() })
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,103 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+use serde::Serialize;
+
+use super::{
+    block::Block,
+    proof_of_work::{MiningStats, PowError},
+};
+
+/// Shared proof-of-work worker pool state. Running jobs are interrupted either
+/// because their template got outdated (`restart`) or on explicit `cancel`.
+pub struct Miner {
+    threads: usize,
+    epoch: AtomicU64,
+    cancellations: AtomicU64,
+    active_jobs: AtomicUsize,
+    last_stats: Mutex<Option<MiningStats>>,
+}
+
+#[derive(Serialize)]
+pub struct MinerStatus {
+    pub threads: usize,
+    pub active_jobs: usize,
+    pub last_job: Option<MiningStats>,
+}
+
+impl Miner {
+    pub fn new(threads: usize) -> Self {
+        Miner {
+            threads: threads.max(1),
+            epoch: AtomicU64::new(0),
+            cancellations: AtomicU64::new(0),
+            active_jobs: AtomicUsize::new(0),
+            last_stats: Mutex::new(None),
+        }
+    }
+
+    pub fn available_threads() -> usize {
+        thread::available_parallelism()
+            .map(|v| v.get())
+            .unwrap_or(1)
+    }
+
+    /// Current template generation, must be read before a block template is collected
+    pub fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::SeqCst)
+    }
+
+    /// Marks running templates as outdated, e.g. a new tip arrived or the mempool changed
+    pub fn restart(&self) {
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn cancel(&self) {
+        self.cancellations.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Mines `block` collected at `epoch`. Returns `PowError::Stale` if the template got
+    /// outdated in the meantime and `PowError::Cancelled` if the job was cancelled.
+    pub fn mine(&self, mut block: Block, epoch: u64) -> Result<Block, PowError> {
+        let cancellations = self.cancellations.load(Ordering::SeqCst);
+
+        if self.epoch() != epoch {
+            return Err(PowError::Stale);
+        }
+
+        self.active_jobs.fetch_add(1, Ordering::SeqCst);
+
+        let result = block.mine(self.threads, &|| {
+            self.epoch() != epoch || self.cancellations.load(Ordering::SeqCst) != cancellations
+        });
+
+        self.active_jobs.fetch_sub(1, Ordering::SeqCst);
+
+        match result {
+            Ok(stats) => {
+                *self.last_stats.lock().unwrap() = Some(stats);
+
+                Ok(block)
+            }
+            Err(PowError::Cancelled)
+                if self.cancellations.load(Ordering::SeqCst) == cancellations =>
+            {
+                Err(PowError::Stale)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn status(&self) -> MinerStatus {
+        MinerStatus {
+            threads: self.threads,
+            active_jobs: self.active_jobs.load(Ordering::SeqCst),
+            last_job: *self.last_stats.lock().unwrap(),
+        }
+    }
+}
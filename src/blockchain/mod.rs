@@ -7,25 +7,70 @@ use kv::{Bucket, Raw};
 use p256::ecdsa::SigningKey;
 use std::{collections::HashMap, error, fmt};
 
-use self::{block::Block, proof_of_work::ProofOfWork, transaction::Transaction};
+use self::{
+    address::Address,
+    block::{Block, BlockHeader, BlockIndex},
+    chain_params::CHAIN_PARAMS,
+    proof_of_work::ProofOfWork,
+    transaction::{MultisigError, Transaction},
+    tx_index::TxIndex,
+    utxo_set::UTXOSet,
+    validation::BlockValidator,
+};
 
+pub(crate) mod address;
 pub(crate) mod block;
+pub(crate) mod chain_params;
+pub(crate) mod coin_selection;
+pub(crate) mod hd_wallet;
+pub(crate) mod history;
+pub(crate) mod keystore;
+pub(crate) mod mempool;
+pub(crate) mod merkle_tree;
+pub(crate) mod miner;
 pub(crate) mod proof_of_work;
+pub(crate) mod reorg;
+pub(crate) mod script;
 pub(crate) mod transaction;
+pub(crate) mod tx_builder;
+pub(crate) mod tx_index;
 pub(crate) mod utxo_set;
+pub(crate) mod validation;
 pub(crate) mod wallet;
-pub(crate) mod merkle_tree;
+pub(crate) mod watch_only;
+
+#[derive(Debug, Clone)]
+pub struct OrphanBlockError;
+
+impl fmt::Display for OrphanBlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Parent of the block is unknown")
+    }
+}
+
+impl error::Error for OrphanBlockError {}
+
+#[derive(Debug, Clone)]
+pub struct DuplicateBlockError;
+
+impl fmt::Display for DuplicateBlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Block is already stored")
+    }
+}
+
+impl error::Error for DuplicateBlockError {}
 
 #[derive(Debug, Clone)]
-struct BadTransactionError;
+pub struct InvalidBlockError;
 
-impl fmt::Display for BadTransactionError {
+impl fmt::Display for InvalidBlockError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Transaction verifying error")
+        write!(f, "Block is invalid or descends from an invalid one")
     }
 }
 
-impl error::Error for BadTransactionError {}
+impl error::Error for InvalidBlockError {}
 
 #[derive(Clone)]
 pub struct Blockchain<'a> {
@@ -41,40 +86,30 @@ struct IteratorState<'a> {
 }
 
 impl<'a> Blockchain<'a> {
-    pub fn new(address: Option<String>, store: &'a AppStore) -> Result<Blockchain<'a>> {
+    pub fn new(address: Option<Address>, store: &'a AppStore) -> Result<Blockchain<'a>> {
         let bucket_name = &BLOCKS_BUCKET.to_string();
 
-        let tip_hash: HashHex;
-        if store.0.buckets().contains(bucket_name) {
+        let tip_hash: HashHex = if store.0.buckets().contains(bucket_name) {
             let bucket = store.0.bucket::<Raw, Raw>(Some(BLOCKS_BUCKET))?;
 
-            tip_hash = bucket
+            bucket
                 .get(b"1")?
                 .expect("Tip hash is not found. Try to remove store and re-init blockchain")
                 .to_vec()
-                .into();
+                .into()
         } else {
             let init_chain = || -> Result<HashHex> {
-                let blocks_bucket = store.0.bucket::<Raw, Raw>(Some(BLOCKS_BUCKET))?;
-
                 let genesis_block = Block::new_genesis(
                     address.ok_or("Blockchain is not initialized yet and address is undefined")?,
                     store,
                 )?;
 
-                blocks_bucket.transaction(|txn| {
-                    let raw_block: Raw = genesis_block.clone().into();
-
-                    txn.set(genesis_block.hash.0.as_slice(), raw_block)?;
-                    txn.set(b"1", genesis_block.hash.0.as_slice())?;
-
-                    Ok(())
-                })?;
+                Self::save_genesis(store, &genesis_block)?;
 
                 Ok(genesis_block.hash)
             };
 
-            tip_hash = init_chain().map_err(|e| {
+            init_chain().inspect_err(|_| {
                 store
                     .0
                     .drop_bucket(bucket_name)
@@ -84,10 +119,8 @@ impl<'a> Blockchain<'a> {
                         Ok(())
                     })
                     .ok();
-
-                e
-            })?;
-        }
+            })?
+        };
 
         Ok(Blockchain {
             iterator_state: IteratorState {
@@ -99,6 +132,35 @@ impl<'a> Blockchain<'a> {
         })
     }
 
+    /// Starts the chain from the genesis block of another node
+    pub fn from_genesis(genesis_block: Block, store: &'a AppStore) -> Result<Blockchain<'a>> {
+        if Self::exists(store) {
+            return Err("Blockchain already exists".into());
+        }
+
+        BlockValidator::validate_genesis(&genesis_block)?;
+        Self::save_genesis(store, &genesis_block)?;
+
+        let blockchain = Self::new(None, store)?;
+
+        let utxo_set = UTXOSet {
+            blockchain: &blockchain,
+        };
+        utxo_set.update(&genesis_block)?;
+
+        Ok(blockchain)
+    }
+
+    fn save_genesis(store: &AppStore, genesis_block: &Block) -> Result<()> {
+        let chain_work = ProofOfWork::work(genesis_block.header.bits);
+        Self::store_block(store, genesis_block, chain_work)?;
+
+        let heights_bucket = store.get_heights_bucket()?;
+        heights_bucket.set(0_u64.to_be_bytes().to_vec(), genesis_block.hash.to_vec())?;
+
+        Self::write_tip(store, &genesis_block.hash)
+    }
+
     pub fn exists(store: &AppStore) -> bool {
         let store = &store.0;
 
@@ -113,39 +175,222 @@ impl<'a> Blockchain<'a> {
         false
     }
 
-    pub fn add_block(&mut self, mut transactions: Vec<Transaction>) -> Result<Block> {
+    /// Points the blocks bucket tip key to `hash`
+    fn write_tip(store: &AppStore, hash: &HashHex) -> Result<()> {
+        let bucket = store.get_blocks_bucket()?;
+        bucket.set(b"1".to_vec(), Raw::from(hash.0.as_slice()))?;
+
+        Ok(())
+    }
+
+    pub fn get_block(&self, hash: &HashHex) -> Result<Option<Block>> {
+        let bucket = self.store.get_blocks_bucket()?;
+
+        let block = bucket.get(hash.to_vec())?.map(Block::from);
+
+        Ok(block)
+    }
+
+    /// Whether the body of the block is stored, its header may be known without it
+    pub fn has_block(&self, hash: &HashHex) -> Result<bool> {
         let bucket = self.store.get_blocks_bucket()?;
 
-        let last_hash: HashHex = bucket
-            .get(b"1".to_vec())?
-            .expect("Tip block hash value is None")
-            .to_vec()
-            .into();
+        Ok(bucket.contains(hash.to_vec())?)
+    }
+
+    pub fn get_block_index(&self, hash: &HashHex) -> Result<Option<BlockIndex>> {
+        let bucket = self.store.get_headers_bucket()?;
+
+        let index = bucket.get(hash.to_vec())?.map(BlockIndex::from);
+
+        Ok(index)
+    }
+
+    pub fn get_header(&self, hash: &HashHex) -> Result<Option<BlockHeader>> {
+        let header = self.get_block_index(hash)?.map(|index| index.header);
+
+        Ok(header)
+    }
+
+    pub fn block_height(&self, hash: &HashHex) -> Result<u64> {
+        let index = self.get_block_index(hash)?.ok_or("Block is not found")?;
+
+        Ok(index.height)
+    }
+
+    pub fn height(&self) -> Result<u64> {
+        self.block_height(&self.tip)
+    }
+
+    pub fn get_hash_by_height(&self, height: u64) -> Result<Option<HashHex>> {
+        let bucket = self.store.get_heights_bucket()?;
+
+        let hash = bucket
+            .get(height.to_be_bytes().to_vec())?
+            .map(HashHex::from);
+
+        Ok(hash)
+    }
+
+    pub fn get_block_by_height(&self, height: u64) -> Result<Option<Block>> {
+        match self.get_hash_by_height(height)? {
+            Some(hash) => self.get_block(&hash),
+            None => Ok(None),
+        }
+    }
+
+    pub fn is_main_chain(&self, hash: &HashHex) -> Result<bool> {
+        let index = match self.get_block_index(hash)? {
+            Some(v) => v,
+            None => return Ok(false),
+        };
+
+        Ok(self.get_hash_by_height(index.height)?.as_ref() == Some(hash))
+    }
+
+    /// Main chain hashes from the tip down to the genesis block. The last ten blocks
+    /// are listed one by one, then the step doubles each time.
+    pub fn block_locator(&self) -> Result<Vec<HashHex>> {
+        let mut locator = Vec::new();
+        let mut height = self.height()?;
+        let mut step = 1;
 
-        for tx in transactions.iter_mut() {
-            if !self.verify_transaction(tx) {
-                println!("[!] Transactions verification is not passed");
-                return Err(Box::new(BadTransactionError));
+        loop {
+            let hash = self
+                .get_hash_by_height(height)?
+                .ok_or("Indexed block is not found")?;
+            locator.push(hash);
+
+            if height == 0 {
+                break;
             }
+
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+
+        Ok(locator)
+    }
+
+    /// Up to `limit` main chain headers after the first `locator` hash on the main chain,
+    /// starting with the genesis one when none of them is there
+    pub fn headers_after(&self, locator: &[HashHex], limit: usize) -> Result<Vec<BlockHeader>> {
+        let mut height = 0;
+        for hash in locator.iter() {
+            if self.is_main_chain(hash)? {
+                height = self.block_height(hash)? + 1;
+                break;
+            }
+        }
+
+        let mut headers = Vec::new();
+        while headers.len() < limit {
+            let hash = match self.get_hash_by_height(height)? {
+                Some(v) => v,
+                None => break,
+            };
+
+            headers.push(
+                self.get_header(&hash)?
+                    .ok_or("Indexed block is not found")?,
+            );
+            height += 1;
+        }
+
+        Ok(headers)
+    }
+
+    /// Main chain blocks with heights in `from..=to`, in ascending order
+    pub fn get_blocks_range(&self, from: u64, to: u64) -> Result<Vec<Block>> {
+        let bucket = self.store.get_heights_bucket()?;
+
+        let mut blocks = Vec::new();
+        if from > to {
+            return Ok(blocks);
         }
 
-        let new_block: Block = Block::new(last_hash, transactions);
+        // Range end is exclusive
+        let end = to.saturating_add(1);
+        for item in bucket.iter_range(from.to_be_bytes().to_vec(), end.to_be_bytes().to_vec()) {
+            let hash: Vec<u8> = item?.value()?;
+            let block = self
+                .get_block(&hash.into())?
+                .ok_or("Indexed block is not found")?;
 
-        bucket.transaction(|txn| {
-            let raw_block: Raw = new_block.clone().into();
+            blocks.push(block);
+        }
 
-            txn.set(new_block.hash.0.clone(), raw_block)?;
-            txn.set(b"1".to_vec(), new_block.hash.0.as_slice())?;
+        Ok(blocks)
+    }
 
-            Ok(())
-        })?;
+    /// Difficulty bits which a block at `height` on top of `parent` must carry
+    pub fn expected_bits(&self, parent: &BlockHeader, height: u64) -> Result<u32> {
+        let interval = CHAIN_PARAMS.retarget_interval;
+
+        if !height.is_multiple_of(interval) {
+            return Ok(parent.bits);
+        }
+
+        let mut first = parent.clone();
+        for _ in 1..interval {
+            first = self
+                .get_header(&first.prev_hash)?
+                .ok_or("Retarget window block is not found")?;
+        }
 
-        self.tip = new_block.hash.to_owned();
+        let timespan = parent.timestamp.saturating_sub(first.timestamp);
 
-        Ok(new_block)
+        Ok(ProofOfWork::retarget(parent.bits, timespan))
+    }
+
+    /// Difficulty bits of the next block on top of the current tip
+    pub fn next_bits(&self) -> Result<u32> {
+        let tip = self
+            .get_block_index(&self.tip)?
+            .ok_or("Tip block is not found")?;
+
+        self.expected_bits(&tip.header, tip.height + 1)
+    }
+
+    /// Median timestamp of the last blocks ending with `hash`
+    pub fn median_time_past(&self, hash: &HashHex) -> Result<u64> {
+        let mut timestamps = Vec::with_capacity(CHAIN_PARAMS.median_time_span);
+        let mut current = hash.clone();
+
+        while !current.0.is_empty() && timestamps.len() < CHAIN_PARAMS.median_time_span {
+            let header = self.get_header(&current)?.ok_or("Block is not found")?;
+
+            timestamps.push(header.timestamp);
+            current = header.prev_hash;
+        }
+
+        timestamps.sort_unstable();
+
+        Ok(timestamps.get(timestamps.len() / 2).copied().unwrap_or(0))
+    }
+
+    fn validate_bits(&self, header: &BlockHeader, height: u64) -> Result<bool> {
+        let expected = if header.prev_hash.0.is_empty() {
+            CHAIN_PARAMS.genesis_bits
+        } else {
+            let parent = self
+                .get_header(&header.prev_hash)?
+                .ok_or("Parent block is not found")?;
+
+            self.expected_bits(&parent, height)?
+        };
+
+        Ok(header.bits == expected)
     }
 
     pub fn find_transaction(&self, id: &HashHex) -> Option<Transaction> {
+        let tx_index = TxIndex { blockchain: self };
+        if tx_index.is_enabled() {
+            return tx_index.find_transaction(id).ok().flatten();
+        }
+
         let mut iterator = self.to_owned();
 
         loop {
@@ -159,7 +404,7 @@ impl<'a> Blockchain<'a> {
             match tx {
                 Some(v) => return Some(v.clone()),
                 None => {
-                    if block.prev_hash.0.is_empty() {
+                    if block.header.prev_hash.0.is_empty() {
                         break;
                     }
                 }
@@ -169,36 +414,77 @@ impl<'a> Blockchain<'a> {
         None
     }
 
-    pub fn sign_transaction(&self, tx: &mut Transaction, private_key: &SigningKey) {
-        let mut prev_txs: HashMap<HashHex, Transaction> = tx
-            .inputs
-            .iter()
-            .map(|input| {
-                let prev_tx = self.find_transaction(&input.tx_id).unwrap();
+    /// Height of the main chain block which contains the transaction `id`
+    pub fn find_transaction_height(&self, id: &HashHex) -> Result<Option<u64>> {
+        let tx_index = TxIndex { blockchain: self };
+        if tx_index.is_enabled() {
+            return match tx_index.get(id)? {
+                Some(location) => Ok(Some(self.block_height(&location.block_hash)?)),
+                None => Ok(None),
+            };
+        }
+
+        let block = self
+            .to_owned()
+            .find(|block| block.transactions.iter().any(|tx| tx.id == *id));
 
-                (prev_tx.id.clone(), prev_tx)
-            })
-            .collect();
+        Ok(block.map(|block| block.height))
+    }
+
+    pub fn sign_transaction(&self, tx: &mut Transaction, private_key: &SigningKey) -> Result<()> {
+        let mut prev_txs = self.spent_transactions(tx)?;
 
         tx.sign(&mut prev_txs, private_key);
+
+        Ok(())
     }
 
-    pub fn verify_transaction(&self, tx: &mut Transaction) -> bool {
+    /// Adds a multisig signature of `private_key`, spent outputs have to be unspent
+    pub fn cosign_transaction(&self, tx: &mut Transaction, private_key: &SigningKey) -> Result<()> {
+        let utxo_set = UTXOSet { blockchain: self };
+
+        let mut prev_txs = HashMap::new();
+        for input in tx.inputs.iter() {
+            if utxo_set
+                .get_output(&input.tx_id, input.output_index)?
+                .is_none()
+            {
+                return Err(MultisigError::UnknownOutput.into());
+            }
+
+            let prev_tx = self
+                .find_transaction(&input.tx_id)
+                .ok_or(MultisigError::UnknownOutput)?;
+
+            prev_txs.insert(prev_tx.id.clone(), prev_tx);
+        }
+
+        tx.cosign(&prev_txs, private_key)
+    }
+
+    pub fn verify_transaction(&self, tx: &mut Transaction) -> Result<bool> {
         if tx.is_coinbase() {
-            return true;
+            return Ok(true);
         }
 
-        let prev_txs: HashMap<HashHex, Transaction> = tx
-            .inputs
-            .iter()
-            .map(|input| {
-                let prev_tx = self.find_transaction(&input.tx_id).unwrap();
+        let prev_txs = self.spent_transactions(tx)?;
+
+        Ok(tx.verify(&prev_txs))
+    }
+
+    /// Transactions of the outputs spent by `tx`, by id
+    fn spent_transactions(&self, tx: &Transaction) -> Result<HashMap<HashHex, Transaction>> {
+        let mut prev_txs = HashMap::new();
+
+        for input in tx.inputs.iter() {
+            let prev_tx = self
+                .find_transaction(&input.tx_id)
+                .ok_or("Spent transaction is not found")?;
 
-                (prev_tx.id.clone(), prev_tx)
-            })
-            .collect();
+            prev_txs.insert(prev_tx.id.clone(), prev_tx);
+        }
 
-        tx.verify(&prev_txs)
+        Ok(prev_txs)
     }
 }
 
@@ -251,13 +537,26 @@ impl<'a> Iterator for Blockchain<'a> {
 
         let block: Block = raw_block.into();
 
-        let proof_of_work = ProofOfWork::new(&block);
+        let proof_of_work = ProofOfWork::new(&block.header);
 
         if !proof_of_work.validate() {
             panic!("Block proof-of-work validation error");
         }
 
-        state.current_hash = Some(block.prev_hash.clone());
+        if Block::hash_transactions(&block.transactions).as_ref() != Some(&block.header.merkle_root)
+        {
+            panic!("Block merkle root validation error");
+        }
+
+        state.current_hash = Some(block.header.prev_hash.clone());
+
+        let bits_valid = self
+            .validate_bits(&block.header, block.height)
+            .expect("Block difficulty calculation during iterating error");
+
+        if !bits_valid {
+            panic!("Block difficulty validation error");
+        }
 
         Some(block)
     }
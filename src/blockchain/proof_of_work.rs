@@ -1,98 +1,246 @@
-use std::cmp::Ordering;
+use std::{
+    cmp::Ordering,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering as AtomicOrdering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
 use num_bigint::BigUint;
+use serde::Serialize;
 use sha2::{Digest, Sha256};
 
-use crate::{blockchain::block, utils::HashHex};
+use crate::{
+    blockchain::block::{BlockHeader, HEADER_SIZE},
+    utils::HashHex,
+};
+
+use super::chain_params::CHAIN_PARAMS;
 
-const TARGET_BITS: u16 = 18;
 const MAX_NONCE: u64 = u64::MAX;
+const CANCEL_CHECK_INTERVAL: u64 = 1024;
 
 #[derive(Debug)]
 pub enum PowError {
     HashIsNotCreated,
+    Cancelled,
+    Stale,
+}
+
+impl std::fmt::Display for PowError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PowError::HashIsNotCreated => {
+                write!(f, "Nonce space is exhausted without a valid hash")
+            }
+            PowError::Cancelled => write!(f, "Mining is cancelled"),
+            PowError::Stale => write!(f, "Block template became outdated during mining"),
+        }
+    }
+}
+
+impl std::error::Error for PowError {}
+
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+pub struct MiningStats {
+    pub hashes: u64,
+    pub elapsed_millis: u64,
+    /// Hashes per second
+    pub hashrate: u64,
+}
+
+impl MiningStats {
+    fn new(hashes: u64, elapsed: Duration) -> Self {
+        let elapsed_millis = elapsed.as_millis() as u64;
+
+        MiningStats {
+            hashes,
+            elapsed_millis,
+            hashrate: hashes * 1000 / elapsed_millis.max(1),
+        }
+    }
 }
 
 pub struct ProofOfWork<'a> {
-    block: &'a block::Block,
+    header: &'a BlockHeader,
     target: BigUint,
 }
 
 impl<'a> ProofOfWork<'a> {
-    pub fn new(block: &block::Block) -> ProofOfWork {
-        let mut target = BigUint::new(vec![1]);
+    pub fn new(header: &BlockHeader) -> ProofOfWork<'_> {
+        let target = Self::target_from_bits(header.bits);
+
+        println!("[!] Proof-of-Work target bits: {:#010x}", header.bits);
 
-        if TARGET_BITS > 255 {
-            panic!("TARGET_BITS must be lower than 256");
+        ProofOfWork { header, target }
+    }
+
+    /// Expands compact `bits` (1 byte exponent, 3 bytes mantissa) into a full target
+    pub fn target_from_bits(bits: u32) -> BigUint {
+        let exponent = bits >> 24;
+        let mantissa = BigUint::from(bits & 0x007f_ffff);
+
+        if exponent <= 3 {
+            mantissa >> (8 * (3 - exponent))
+        } else {
+            mantissa << (8 * (exponent - 3))
         }
+    }
 
-        target <<= 256 - TARGET_BITS;
+    pub fn bits_from_target(target: &BigUint) -> u32 {
+        let mut size = target.bits().div_ceil(8) as u32;
 
-        println!("[!] Proof-of-Work target bits: {}", TARGET_BITS);
+        let mut mantissa = if size <= 3 {
+            target << (8 * (3 - size))
+        } else {
+            target >> (8 * (size - 3))
+        }
+        .to_u32_digits()
+        .first()
+        .copied()
+        .unwrap_or(0);
+
+        // The sign bit of the mantissa must stay clear
+        if mantissa & 0x0080_0000 != 0 {
+            mantissa >>= 8;
+            size += 1;
+        }
 
-        ProofOfWork { block, target }
+        mantissa | (size << 24)
     }
 
-    pub fn run(&self) -> Result<(u64, HashHex), PowError> {
-        let mut hash_int: BigUint;
-        let mut hash: Vec<u8> = vec![];
-        let mut nonce = 0_u64;
+    /// Expected amount of hashes to meet the target of `bits`, i.e. 2^256 / (target + 1)
+    pub fn work(bits: u32) -> BigUint {
+        let target = Self::target_from_bits(bits);
 
-        println!("[!] Starting to mine the new block...");
+        (BigUint::from(1_u8) << 256) / (target + 1_u8)
+    }
 
-        while nonce < MAX_NONCE {
-            let data = self.prepare_data(nonce);
-            let mut hasher = Sha256::new();
-            hasher.update(data);
+    /// Scales the previous target by the ratio of observed and expected window time.
+    /// The adjustment is clamped to a factor of 4 per retarget, like in Bitcoin.
+    pub fn retarget(prev_bits: u32, actual_timespan: u64) -> u32 {
+        let expected_timespan =
+            (CHAIN_PARAMS.retarget_interval - 1) * CHAIN_PARAMS.target_block_time;
+        let actual_timespan = actual_timespan.clamp(expected_timespan / 4, expected_timespan * 4);
 
-            let result = hasher.finalize();
-            let hash_bytes: [u8; 32] = result.into();
+        let target = Self::target_from_bits(prev_bits) * actual_timespan / expected_timespan;
+        let pow_limit = Self::target_from_bits(CHAIN_PARAMS.pow_limit_bits);
 
-            hash_int = BigUint::from_bytes_be(&hash_bytes);
+        let new_bits = Self::bits_from_target(&target.min(pow_limit));
 
-            if hash_int.cmp(&self.target) == Ordering::Less {
-                hash = hash_bytes.to_vec();
-                break;
-            } else {
-                nonce += 1;
-            };
-        }
+        println!(
+            "[!] Difficulty retarget: {:#010x} -> {:#010x} (window {} ms, expected {} ms)",
+            prev_bits, new_bits, actual_timespan, expected_timespan
+        );
 
-        if !hash.is_empty() {
-            let hash_hex = HashHex(hash);
+        new_bits
+    }
 
-            println!(
-                "[!] Block mined: (nonce, hash) = ({}, {})",
-                nonce,
-                serde_json::to_string(&hash_hex).unwrap()
-            );
-            Ok((nonce, hash_hex))
-        } else {
-            Err(PowError::HashIsNotCreated)
+    /// Splits the nonce space between `threads` workers, each of them stepping over
+    /// the nonces of the others. `is_cancelled` is polled periodically by every worker.
+    pub fn run(
+        &self,
+        threads: usize,
+        is_cancelled: &(dyn Fn() -> bool + Sync),
+    ) -> Result<(u64, HashHex, MiningStats), PowError> {
+        let threads = threads.max(1) as u64;
+        let prefix = self.prepare_prefix();
+
+        let found: Mutex<Option<(u64, Vec<u8>)>> = Mutex::new(None);
+        let stop = AtomicBool::new(false);
+        let hashes = AtomicU64::new(0);
+
+        println!(
+            "[!] Starting to mine the new block with {} threads...",
+            threads
+        );
+
+        let started = Instant::now();
+
+        thread::scope(|scope| {
+            for worker in 0..threads {
+                let (prefix, found, stop, hashes) = (&prefix, &found, &stop, &hashes);
+
+                scope.spawn(move || {
+                    let mut data = prefix.clone();
+                    let mut nonce = worker;
+                    let mut tried = 0_u64;
+
+                    while nonce < MAX_NONCE {
+                        if tried.is_multiple_of(CANCEL_CHECK_INTERVAL) {
+                            if stop.load(AtomicOrdering::Relaxed) {
+                                break;
+                            }
+
+                            if is_cancelled() {
+                                stop.store(true, AtomicOrdering::Relaxed);
+                                break;
+                            }
+                        }
+
+                        data.truncate(prefix.len());
+                        data.extend_from_slice(nonce.to_le_bytes().as_slice());
+
+                        let hash_bytes: [u8; 32] = Sha256::digest(&data).into();
+                        tried += 1;
+
+                        let hash_int = BigUint::from_bytes_be(&hash_bytes);
+
+                        if hash_int.cmp(&self.target) == Ordering::Less {
+                            stop.store(true, AtomicOrdering::Relaxed);
+                            found
+                                .lock()
+                                .unwrap()
+                                .get_or_insert((nonce, hash_bytes.to_vec()));
+                            break;
+                        }
+
+                        nonce = match nonce.checked_add(threads) {
+                            Some(v) => v,
+                            None => break,
+                        };
+                    }
+
+                    hashes.fetch_add(tried, AtomicOrdering::Relaxed);
+                });
+            }
+        });
+
+        let stats = MiningStats::new(hashes.into_inner(), started.elapsed());
+
+        println!(
+            "[!] Mining finished: {} hashes in {} ms ({} H/s)",
+            stats.hashes, stats.elapsed_millis, stats.hashrate
+        );
+
+        match found.into_inner().unwrap() {
+            Some((nonce, hash)) => {
+                let hash_hex = HashHex(hash);
+
+                println!(
+                    "[!] Block mined: (nonce, hash) = ({}, {})",
+                    nonce,
+                    serde_json::to_string(&hash_hex).unwrap()
+                );
+                Ok((nonce, hash_hex, stats))
+            }
+            None if is_cancelled() => Err(PowError::Cancelled),
+            None => Err(PowError::HashIsNotCreated),
         }
     }
 
     pub fn validate(&self) -> bool {
-        let data = self.prepare_data(self.block.nonce);
-        let mut hasher = Sha256::new();
-        hasher.update(data);
-
-        let hash_bytes: [u8; 32] = hasher.finalize().into();
-
-        let hash_int = BigUint::from_bytes_be(&hash_bytes);
+        let hash_int = BigUint::from_bytes_be(&self.header.hash().0);
 
         hash_int.cmp(&self.target) == Ordering::Less
     }
 
-    fn prepare_data(&self, nonce: u64) -> Vec<u8> {
-        let data = [
-            &self.block.prev_hash.0,
-            &self.block.hash_transactions(),
-            self.block.timestamp.as_bytes(),
-            TARGET_BITS.to_ne_bytes().as_slice(),
-            nonce.to_ne_bytes().as_slice(),
-        ]
-        .concat();
+    /// Encoded header without the trailing nonce, which is the only part changing during mining
+    fn prepare_prefix(&self) -> Vec<u8> {
+        let mut data = self.header.serialize();
+        data.truncate(HEADER_SIZE - 8);
 
         data
     }
@@ -0,0 +1,360 @@
+use std::collections::{HashMap, HashSet};
+
+use num_bigint::BigUint;
+use serde::Serialize;
+
+use crate::{
+    store::AppStore,
+    utils::{HashHex, Result},
+};
+
+use super::{
+    block::{Block, BlockHeader, BlockIndex},
+    proof_of_work::ProofOfWork,
+    tx_index::TxIndex,
+    utxo_set::UTXOSet,
+    validation::{BlockValidator, ValidationError},
+    Blockchain, DuplicateBlockError, InvalidBlockError, OrphanBlockError,
+};
+
+/// Blocks which left and joined the main chain after `Blockchain::add_block`
+#[derive(Debug, Default)]
+pub struct ChainUpdate {
+    /// In disconnection order, starting from the former tip
+    pub disconnected: Vec<Block>,
+    /// In connection order, ending with the new tip
+    pub connected: Vec<Block>,
+}
+
+#[derive(Serialize)]
+pub struct ChainTip {
+    pub hash: HashHex,
+    pub height: u64,
+    /// Hex encoded cumulative work of the branch
+    pub chain_work: String,
+    pub active: bool,
+}
+
+impl<'a> Blockchain<'a> {
+    /// Stores a mined block on any known branch. If the branch of the block gets
+    /// more cumulative work than the current one, the chain is reorganized onto it.
+    pub fn add_block(&mut self, new_block: Block) -> Result<ChainUpdate> {
+        if self.has_block(&new_block.hash)? {
+            return Err(Box::new(DuplicateBlockError));
+        }
+
+        if self.is_invalid(&new_block.hash)? || self.is_invalid(&new_block.header.prev_hash)? {
+            return Err(Box::new(InvalidBlockError));
+        }
+
+        if !self.has_block(&new_block.header.prev_hash)? {
+            return Err(Box::new(OrphanBlockError));
+        }
+
+        let parent = self
+            .get_block_index(&new_block.header.prev_hash)?
+            .ok_or("Parent block is not found")?;
+
+        // Height is stored along with the block, so it is checked before that
+        if new_block.height != parent.height + 1 {
+            return Err(ValidationError::BadHeight.into());
+        }
+
+        let validator = BlockValidator { blockchain: self };
+        validator
+            .validate_header(&new_block.header, &new_block.hash)
+            .inspect_err(|e| {
+                println!("[!] Block header validation is not passed: {}", e);
+            })?;
+
+        // A body which doesn't belong to the header is refused before it is stored,
+        // so it can't take the place of the real one
+        BlockValidator::check_body(&new_block).inspect_err(|e| {
+            println!("[!] Block body validation is not passed: {}", e);
+        })?;
+
+        let chain_work = parent.chain_work + ProofOfWork::work(new_block.header.bits);
+        Self::store_block(self.store, &new_block, chain_work.clone())?;
+
+        let tip = self
+            .get_block_index(&self.tip)?
+            .ok_or("Tip block is not found")?;
+
+        if chain_work <= tip.chain_work {
+            println!(
+                "[!] Block {} is stored on a side branch",
+                hex::encode(&new_block.hash.0)
+            );
+
+            return Ok(ChainUpdate::default());
+        }
+
+        self.reorganize(&new_block.hash)
+    }
+
+    /// Validates `header` against its stored parent and keeps it without the block body,
+    /// so the body can be downloaded later
+    pub fn store_header(&self, header: &BlockHeader) -> Result<BlockIndex> {
+        let hash = header.hash();
+
+        if let Some(index) = self.get_block_index(&hash)? {
+            return Ok(index);
+        }
+
+        if self.is_invalid(&hash)? || self.is_invalid(&header.prev_hash)? {
+            return Err(Box::new(InvalidBlockError));
+        }
+
+        let parent = self
+            .get_block_index(&header.prev_hash)?
+            .ok_or(OrphanBlockError)?;
+
+        let validator = BlockValidator { blockchain: self };
+        validator.validate_header(header, &hash)?;
+
+        let index = BlockIndex {
+            header: header.clone(),
+            height: parent.height + 1,
+            chain_work: parent.chain_work + ProofOfWork::work(header.bits),
+        };
+
+        self.store
+            .get_headers_bucket()?
+            .set(hash.to_vec(), index.clone())?;
+
+        Ok(index)
+    }
+
+    /// Every stored block which is not a parent of another one
+    pub fn chain_tips(&self) -> Result<Vec<ChainTip>> {
+        let bucket = self.store.get_headers_bucket()?;
+
+        let mut indexes = Vec::new();
+        for item in bucket.iter() {
+            let index = BlockIndex::from(item?.value::<kv::Raw>()?);
+
+            // Headers whose bodies are not downloaded yet are not a part of any branch
+            if self.has_block(&index.hash())? {
+                indexes.push(index);
+            }
+        }
+
+        let parents: HashSet<HashHex> = indexes
+            .iter()
+            .map(|index| index.header.prev_hash.clone())
+            .collect();
+
+        let tips = indexes
+            .into_iter()
+            .filter(|index| !parents.contains(&index.hash()))
+            .map(|index| {
+                let hash = index.hash();
+
+                ChainTip {
+                    active: hash == self.tip,
+                    hash,
+                    height: index.height,
+                    chain_work: index.chain_work.to_str_radix(16),
+                }
+            })
+            .collect();
+
+        Ok(tips)
+    }
+
+    fn reorganize(&mut self, new_tip: &HashHex) -> Result<ChainUpdate> {
+        let (to_disconnect, to_connect) = self.find_fork(&self.tip.clone(), new_tip)?;
+
+        if !to_disconnect.is_empty() {
+            println!(
+                "[!] Reorganization: {} blocks to disconnect, {} blocks to connect",
+                to_disconnect.len(),
+                to_connect.len()
+            );
+        }
+
+        let mut update = ChainUpdate::default();
+
+        for _ in to_disconnect.iter() {
+            update.disconnected.push(self.disconnect_block()?);
+        }
+
+        for hash in to_connect.iter() {
+            let block = self.get_block(hash)?.ok_or("Block is not found")?;
+
+            if let Err(e) = self.connect_block(&block, true) {
+                println!("[!] Block validation is not passed: {}", e);
+
+                // Body matches the header by now, so only a rule break marks it invalid
+                if e.is::<ValidationError>() {
+                    self.invalidate_block(hash)?;
+                }
+                self.rollback(&update)?;
+
+                return Err(e);
+            }
+
+            update.connected.push(block);
+        }
+
+        Ok(update)
+    }
+
+    /// Returns the main chain blocks to disconnect (from `old_tip` down) and the
+    /// branch blocks to connect (up to `new_tip`) above their common ancestor
+    fn find_fork(
+        &self,
+        old_tip: &HashHex,
+        new_tip: &HashHex,
+    ) -> Result<(Vec<HashHex>, Vec<HashHex>)> {
+        let mut old = self.get_block_index(old_tip)?.ok_or("Block is not found")?;
+        let mut new = self.get_block_index(new_tip)?.ok_or("Block is not found")?;
+
+        let mut to_disconnect = Vec::new();
+        let mut to_connect = Vec::new();
+
+        while old.hash() != new.hash() {
+            if old.height >= new.height {
+                to_disconnect.push(old.hash());
+                old = self
+                    .get_block_index(&old.header.prev_hash)?
+                    .ok_or("Fork point is not found")?;
+            } else {
+                to_connect.push(new.hash());
+                new = self
+                    .get_block_index(&new.header.prev_hash)?
+                    .ok_or("Fork point is not found")?;
+            }
+        }
+
+        to_connect.reverse();
+
+        Ok((to_disconnect, to_connect))
+    }
+
+    /// Brings back the main chain which was in place before a failed reorganization
+    fn rollback(&mut self, update: &ChainUpdate) -> Result<()> {
+        for _ in update.connected.iter() {
+            self.disconnect_block()?;
+        }
+
+        for block in update.disconnected.iter().rev() {
+            self.connect_block(block, false)?;
+        }
+
+        Ok(())
+    }
+
+    fn connect_block(&mut self, block: &Block, validate: bool) -> Result<()> {
+        if validate {
+            let validator = BlockValidator { blockchain: self };
+            validator.validate(block)?;
+        }
+
+        let utxo_set = UTXOSet { blockchain: self };
+        utxo_set.update(block)?;
+
+        let tx_index = TxIndex { blockchain: self };
+        if tx_index.is_enabled() {
+            tx_index.index_block(block)?;
+        }
+
+        let heights_bucket = self.store.get_heights_bucket()?;
+        heights_bucket.set(block.height.to_be_bytes().to_vec(), block.hash.to_vec())?;
+
+        Self::write_tip(self.store, &block.hash)?;
+        self.tip = block.hash.clone();
+
+        Ok(())
+    }
+
+    /// Moves the tip back to its parent, the chainstate is restored from the undo
+    /// record of the block. Returns the disconnected block, which stays stored.
+    pub fn disconnect_block(&mut self) -> Result<Block> {
+        let block = self.get_block(&self.tip)?.ok_or("Tip block is not found")?;
+
+        if block.header.prev_hash.0.is_empty() {
+            return Err("Genesis block can't be disconnected".into());
+        }
+
+        let utxo_set = UTXOSet { blockchain: self };
+        utxo_set.disconnect(&block)?;
+
+        let tx_index = TxIndex { blockchain: self };
+        if tx_index.is_enabled() {
+            tx_index.deindex_block(&block)?;
+        }
+
+        let heights_bucket = self.store.get_heights_bucket()?;
+        heights_bucket.remove(block.height.to_be_bytes().to_vec())?;
+
+        Self::write_tip(self.store, &block.header.prev_hash)?;
+        self.tip = block.header.prev_hash.clone();
+
+        Ok(block)
+    }
+
+    /// Disconnects main chain blocks until the tip is at `height`
+    pub fn rewind(&mut self, height: u64) -> Result<ChainUpdate> {
+        let mut update = ChainUpdate::default();
+
+        while self.height()? > height {
+            update.disconnected.push(self.disconnect_block()?);
+        }
+
+        Ok(update)
+    }
+
+    /// Forgets an invalid block along with every stored block and header on top of it.
+    /// Their hashes are kept, so neither they nor their children are accepted again.
+    fn invalidate_block(&self, hash: &HashHex) -> Result<()> {
+        let headers_bucket = self.store.get_headers_bucket()?;
+
+        let mut children = HashMap::<HashHex, Vec<HashHex>>::new();
+        for item in headers_bucket.iter() {
+            let index = BlockIndex::from(item?.value::<kv::Raw>()?);
+
+            children
+                .entry(index.header.prev_hash.clone())
+                .or_default()
+                .push(index.hash());
+        }
+
+        let blocks_bucket = self.store.get_blocks_bucket()?;
+        let invalid_bucket = self.store.get_invalid_bucket()?;
+
+        let mut queue = vec![hash.clone()];
+        while let Some(hash) = queue.pop() {
+            blocks_bucket.remove(hash.to_vec())?;
+            headers_bucket.remove(hash.to_vec())?;
+            invalid_bucket.set(hash.to_vec(), vec![])?;
+
+            println!("[!] Block {} is marked invalid", hex::encode(&hash.0));
+
+            queue.extend(children.remove(&hash).unwrap_or_default());
+        }
+
+        Ok(())
+    }
+
+    fn is_invalid(&self, hash: &HashHex) -> Result<bool> {
+        Ok(self.store.get_invalid_bucket()?.contains(hash.to_vec())?)
+    }
+
+    pub(super) fn store_block(store: &AppStore, block: &Block, chain_work: BigUint) -> Result<()> {
+        let index = BlockIndex {
+            header: block.header.clone(),
+            height: block.height,
+            chain_work,
+        };
+
+        store
+            .get_headers_bucket()?
+            .set(block.hash.to_vec(), index)?;
+        store
+            .get_blocks_bucket()?
+            .set(block.hash.to_vec(), kv::Raw::from(block.clone()))?;
+
+        Ok(())
+    }
+}
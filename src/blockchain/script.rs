@@ -0,0 +1,584 @@
+use std::{fmt, str::FromStr};
+
+use p256::{
+    ecdsa::{
+        signature::{Signature as _, Verifier},
+        Signature, VerifyingKey,
+    },
+    EncodedPoint,
+};
+use ripemd::Ripemd160;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+
+use crate::utils::HashHex;
+
+use super::transaction::LOCK_TIME_THRESHOLD;
+
+/// Most items the stack may hold while a script runs
+const MAX_STACK_SIZE: usize = 1000;
+
+/// Most keys a multisig output may list, so that both counts fit `OP_1`..`OP_16`
+pub const MAX_MULTISIG_KEYS: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    /// Pushes the data onto the stack, written as plain hex (`OP_0` when empty)
+    Push(Vec<u8>),
+    /// Pushes a small number, written as `OP_1`..`OP_16`
+    Num(u8),
+    /// Runs the following operations up to `OP_ELSE` or `OP_ENDIF` if the top item is true
+    If,
+    Else,
+    EndIf,
+    Drop,
+    Dup,
+    Sha256,
+    Hash160,
+    Equal,
+    EqualVerify,
+    Verify,
+    CheckSig,
+    /// Checks `m` signatures against `n` keys: `<sigs..> m <keys..> n OP_CHECKMULTISIG`,
+    /// signatures have to follow the order of their keys
+    CheckMultiSig,
+    /// Fails unless the transaction `lock_time` has reached the top number,
+    /// both have to be heights or both timestamps
+    CheckLockTimeVerify,
+    /// Fails right away, marks an output which carries data and can never be spent
+    Return,
+}
+
+/// Sequence of operations, kept in its text form: `OP_DUP OP_HASH160 <hex> ...`
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Script(pub Vec<Op>);
+
+#[derive(Debug, Clone)]
+pub enum ScriptError {
+    UnknownOp(String),
+    BadNumber,
+    BadMultisig(usize, usize),
+    UnbalancedConditional,
+    StackUnderflow,
+    StackOverflow,
+    VerifyFailed,
+    NotPushOnly,
+    FalseResult,
+    Unspendable,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScriptError::UnknownOp(op) => write!(f, "Unknown script operation '{}'", op),
+            ScriptError::BadNumber => write!(f, "Script stack item is not a number"),
+            ScriptError::BadMultisig(required, total) => write!(
+                f,
+                "Multisig can't require {} signatures of {} keys",
+                required, total
+            ),
+            ScriptError::UnbalancedConditional => {
+                write!(f, "Script has unbalanced OP_IF, OP_ELSE or OP_ENDIF")
+            }
+            ScriptError::StackUnderflow => write!(f, "Script stack has not enough items"),
+            ScriptError::StackOverflow => write!(f, "Script stack is too large"),
+            ScriptError::VerifyFailed => write!(f, "Script verify operation failed"),
+            ScriptError::NotPushOnly => write!(f, "Unlocking script may only push data"),
+            ScriptError::FalseResult => write!(f, "Script finished with a false result"),
+            ScriptError::Unspendable => write!(f, "Script is provably unspendable"),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+/// Data a script is checked against, besides its own stack
+pub struct ScriptContext {
+    /// Hash the `OP_CHECKSIG` signatures commit to, see `Transaction::signature_hash`
+    pub sighash: HashHex,
+    /// `lock_time` of the spending transaction
+    pub lock_time: u64,
+}
+
+/// Hash time-locked output: `recipient` takes the coins by revealing the preimage
+/// of `hash`, or `refund` takes them back once `timeout` is reached
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Htlc {
+    /// SHA-256 of the secret preimage
+    pub hash: Vec<u8>,
+    pub recipient: HashHex,
+    /// Lock time (see `LOCK_TIME_THRESHOLD`) the refund is possible from
+    pub timeout: u64,
+    pub refund: HashHex,
+}
+
+pub fn hash160(data: &[u8]) -> Vec<u8> {
+    let sha256 = Sha256::digest(data);
+
+    Ripemd160::digest(sha256).to_vec()
+}
+
+/// Little-endian number without trailing zero bytes, as `OP_CHECKLOCKTIMEVERIFY` reads it
+pub fn encode_number(number: u64) -> Vec<u8> {
+    let mut data = number.to_le_bytes().to_vec();
+
+    while data.last() == Some(&0) {
+        data.pop();
+    }
+
+    data
+}
+
+fn decode_number(data: &[u8]) -> Result<u64, ScriptError> {
+    if data.len() > 8 {
+        return Err(ScriptError::BadNumber);
+    }
+
+    let mut bytes = [0_u8; 8];
+    bytes[..data.len()].copy_from_slice(data);
+
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Checks the P-256 `signature` of `sighash`, `pub_key` is an encoded point
+pub fn verify_signature(sighash: &HashHex, signature: &[u8], pub_key: &[u8]) -> bool {
+    let verify_key = match EncodedPoint::from_bytes(pub_key)
+        .map(|point| VerifyingKey::from_encoded_point(&point))
+    {
+        Ok(Ok(v)) => v,
+        _ => return false,
+    };
+
+    let signature = match Signature::from_bytes(signature) {
+        Ok(v) => v,
+        Err(_) => return false,
+    };
+
+    verify_key.verify(&sighash.0, &signature).is_ok()
+}
+
+impl Script {
+    /// Locks coins to the owner of the key hashed into `pub_key_hash`
+    pub fn new_p2pkh(pub_key_hash: &HashHex) -> Self {
+        Script(vec![
+            Op::Dup,
+            Op::Hash160,
+            Op::Push(pub_key_hash.to_vec()),
+            Op::EqualVerify,
+            Op::CheckSig,
+        ])
+    }
+
+    pub fn new_p2pkh_sig(signature: Vec<u8>, pub_key: Vec<u8>) -> Self {
+        Script(vec![Op::Push(signature), Op::Push(pub_key)])
+    }
+
+    /// Locks coins to `required` signatures of any of `pub_keys`
+    pub fn new_multisig(required: usize, pub_keys: &[Vec<u8>]) -> Result<Self, ScriptError> {
+        if required == 0 || required > pub_keys.len() || pub_keys.len() > MAX_MULTISIG_KEYS {
+            return Err(ScriptError::BadMultisig(required, pub_keys.len()));
+        }
+
+        let mut ops = vec![Op::Num(required as u8)];
+        ops.extend(pub_keys.iter().map(|key| Op::Push(key.clone())));
+        ops.push(Op::Num(pub_keys.len() as u8));
+        ops.push(Op::CheckMultiSig);
+
+        Ok(Script(ops))
+    }
+
+    /// Required signatures count and keys of a standard multisig script
+    pub fn multisig_keys(&self) -> Option<(usize, Vec<Vec<u8>>)> {
+        let (required, rest) = match self.0.as_slice() {
+            [Op::Num(required), rest @ .., Op::Num(_), Op::CheckMultiSig] => (*required, rest),
+            _ => return None,
+        };
+
+        let pub_keys = rest
+            .iter()
+            .map(|op| match op {
+                Op::Push(key) => Some(key.clone()),
+                _ => None,
+            })
+            .collect::<Option<Vec<Vec<u8>>>>()?;
+
+        if Script::new_multisig(required as usize, &pub_keys).ok()? != *self {
+            return None;
+        }
+
+        Some((required as usize, pub_keys))
+    }
+
+    /// `OP_IF OP_SHA256 <hash> OP_EQUALVERIFY OP_DUP OP_HASH160 <recipient>
+    /// OP_ELSE <timeout> OP_CHECKLOCKTIMEVERIFY OP_DROP OP_DUP OP_HASH160 <refund>
+    /// OP_ENDIF OP_EQUALVERIFY OP_CHECKSIG`
+    pub fn new_htlc(htlc: &Htlc) -> Self {
+        Script(vec![
+            Op::If,
+            Op::Sha256,
+            Op::Push(htlc.hash.clone()),
+            Op::EqualVerify,
+            Op::Dup,
+            Op::Hash160,
+            Op::Push(htlc.recipient.to_vec()),
+            Op::Else,
+            Op::Push(encode_number(htlc.timeout)),
+            Op::CheckLockTimeVerify,
+            Op::Drop,
+            Op::Dup,
+            Op::Hash160,
+            Op::Push(htlc.refund.to_vec()),
+            Op::EndIf,
+            Op::EqualVerify,
+            Op::CheckSig,
+        ])
+    }
+
+    /// Claims an HTLC output with the secret `preimage`
+    pub fn new_htlc_claim_sig(signature: Vec<u8>, pub_key: Vec<u8>, preimage: Vec<u8>) -> Self {
+        Script(vec![
+            Op::Push(signature),
+            Op::Push(pub_key),
+            Op::Push(preimage),
+            Op::Num(1),
+        ])
+    }
+
+    /// Takes an HTLC output back after its timeout
+    pub fn new_htlc_refund_sig(signature: Vec<u8>, pub_key: Vec<u8>) -> Self {
+        Script(vec![
+            Op::Push(signature),
+            Op::Push(pub_key),
+            Op::Push(vec![]),
+        ])
+    }
+
+    /// Parameters of a standard HTLC script
+    pub fn htlc(&self) -> Option<Htlc> {
+        let push = |index: usize| match self.0.get(index) {
+            Some(Op::Push(data)) => Some(data.clone()),
+            _ => None,
+        };
+
+        let htlc = Htlc {
+            hash: push(2)?,
+            recipient: push(6)?.into(),
+            timeout: decode_number(&push(8)?).ok()?,
+            refund: push(13)?.into(),
+        };
+
+        // Operations between the parameters have to follow the template too
+        if Script::new_htlc(&htlc) != *self {
+            return None;
+        }
+
+        Some(htlc)
+    }
+
+    /// `OP_RETURN <data>`
+    pub fn new_data(data: Vec<u8>) -> Self {
+        Script(vec![Op::Return, Op::Push(data)])
+    }
+
+    /// Whether the script starts with `OP_RETURN`, so an output locked by it
+    /// is never kept in the UTXO set
+    pub fn is_unspendable(&self) -> bool {
+        self.0.first() == Some(&Op::Return)
+    }
+
+    /// Bytes pushed by an `OP_RETURN` script
+    pub fn data_size(&self) -> usize {
+        self.0
+            .iter()
+            .map(|op| match op {
+                Op::Push(data) => data.len(),
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// Key hash of a standard pay-to-public-key-hash script
+    pub fn p2pkh_hash(&self) -> Option<HashHex> {
+        match self.0.as_slice() {
+            [Op::Dup, Op::Hash160, Op::Push(hash), Op::EqualVerify, Op::CheckSig] => {
+                Some(hash.clone().into())
+            }
+            _ => None,
+        }
+    }
+
+    pub fn is_push_only(&self) -> bool {
+        self.0
+            .iter()
+            .all(|op| matches!(op, Op::Push(_) | Op::Num(_)))
+    }
+
+    /// Runs `script_sig`, then `script_pubkey` on the stack it left.
+    /// Spending is allowed if the top item is true at the end.
+    pub fn verify(
+        script_sig: &Script,
+        script_pubkey: &Script,
+        context: &ScriptContext,
+    ) -> Result<(), ScriptError> {
+        // Otherwise a third party could alter the unlocking script keeping it valid
+        if !script_sig.is_push_only() {
+            return Err(ScriptError::NotPushOnly);
+        }
+
+        let mut interpreter = Interpreter {
+            context,
+            stack: Vec::new(),
+        };
+
+        interpreter.execute(script_sig)?;
+        interpreter.execute(script_pubkey)?;
+
+        match interpreter.stack.last() {
+            Some(top) if Interpreter::is_true(top) => Ok(()),
+            _ => Err(ScriptError::FalseResult),
+        }
+    }
+}
+
+struct Interpreter<'a> {
+    context: &'a ScriptContext,
+    stack: Vec<Vec<u8>>,
+}
+
+impl<'a> Interpreter<'a> {
+    fn execute(&mut self, script: &Script) -> Result<(), ScriptError> {
+        // Conditions of the enclosing `OP_IF`s, operations run only when all of them hold
+        let mut branches: Vec<bool> = Vec::new();
+
+        for op in script.0.iter() {
+            let is_executing = branches.iter().all(|branch| *branch);
+
+            match op {
+                Op::If => {
+                    let condition = is_executing && Self::is_true(&self.pop()?);
+                    branches.push(condition);
+                }
+                Op::Else => {
+                    let branch = branches
+                        .last_mut()
+                        .ok_or(ScriptError::UnbalancedConditional)?;
+                    *branch = !*branch;
+                }
+                Op::EndIf => {
+                    branches.pop().ok_or(ScriptError::UnbalancedConditional)?;
+                }
+                _ if !is_executing => {}
+                Op::Push(data) => self.push(data.clone())?,
+                Op::Num(number) => self.push(vec![*number])?,
+                Op::Drop => {
+                    self.pop()?;
+                }
+                Op::Dup => {
+                    let top = self.stack.last().ok_or(ScriptError::StackUnderflow)?;
+                    self.push(top.clone())?;
+                }
+                Op::Sha256 => {
+                    let data = self.pop()?;
+                    self.push(Sha256::digest(data).to_vec())?;
+                }
+                Op::Hash160 => {
+                    let data = self.pop()?;
+                    self.push(hash160(&data))?;
+                }
+                Op::Equal => {
+                    let equal = self.pop()? == self.pop()?;
+                    self.push_bool(equal)?;
+                }
+                Op::EqualVerify => {
+                    if self.pop()? != self.pop()? {
+                        return Err(ScriptError::VerifyFailed);
+                    }
+                }
+                Op::Verify => {
+                    if !Self::is_true(&self.pop()?) {
+                        return Err(ScriptError::VerifyFailed);
+                    }
+                }
+                Op::CheckSig => {
+                    let pub_key = self.pop()?;
+                    let signature = self.pop()?;
+
+                    let valid = verify_signature(&self.context.sighash, &signature, &pub_key);
+                    self.push_bool(valid)?;
+                }
+                Op::CheckMultiSig => {
+                    let valid = self.check_multisig()?;
+                    self.push_bool(valid)?;
+                }
+                Op::Return => return Err(ScriptError::Unspendable),
+                Op::CheckLockTimeVerify => {
+                    let top = self.stack.last().ok_or(ScriptError::StackUnderflow)?;
+                    let lock_time = decode_number(top)?;
+
+                    let tx_lock_time = self.context.lock_time;
+                    let is_same_kind =
+                        (lock_time < LOCK_TIME_THRESHOLD) == (tx_lock_time < LOCK_TIME_THRESHOLD);
+
+                    if !is_same_kind || tx_lock_time < lock_time {
+                        return Err(ScriptError::VerifyFailed);
+                    }
+                }
+            }
+        }
+
+        if !branches.is_empty() {
+            return Err(ScriptError::UnbalancedConditional);
+        }
+
+        Ok(())
+    }
+
+    fn check_multisig(&mut self) -> Result<bool, ScriptError> {
+        let total = self.pop_number()? as usize;
+        if total > MAX_MULTISIG_KEYS {
+            return Err(ScriptError::BadMultisig(0, total));
+        }
+
+        let mut pub_keys = (0..total)
+            .map(|_| self.pop())
+            .collect::<Result<Vec<Vec<u8>>, ScriptError>>()?;
+        pub_keys.reverse();
+
+        let required = self.pop_number()? as usize;
+        if required > total {
+            return Err(ScriptError::BadMultisig(required, total));
+        }
+
+        let mut signatures = (0..required)
+            .map(|_| self.pop())
+            .collect::<Result<Vec<Vec<u8>>, ScriptError>>()?;
+        signatures.reverse();
+
+        // Every signature takes the next key it matches, skipped keys are never reused
+        let mut keys = pub_keys.iter();
+        let valid = signatures.iter().all(|signature| {
+            keys.any(|key| verify_signature(&self.context.sighash, signature, key))
+        });
+
+        Ok(valid)
+    }
+
+    fn push(&mut self, data: Vec<u8>) -> Result<(), ScriptError> {
+        if self.stack.len() >= MAX_STACK_SIZE {
+            return Err(ScriptError::StackOverflow);
+        }
+
+        self.stack.push(data);
+
+        Ok(())
+    }
+
+    fn push_bool(&mut self, value: bool) -> Result<(), ScriptError> {
+        self.push(if value { vec![1] } else { vec![] })
+    }
+
+    fn pop(&mut self) -> Result<Vec<u8>, ScriptError> {
+        self.stack.pop().ok_or(ScriptError::StackUnderflow)
+    }
+
+    fn pop_number(&mut self) -> Result<u64, ScriptError> {
+        decode_number(&self.pop()?)
+    }
+
+    /// Empty and all-zero items are false
+    fn is_true(data: &[u8]) -> bool {
+        data.iter().any(|byte| *byte != 0)
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Op::Push(data) if data.is_empty() => write!(f, "OP_0"),
+            Op::Push(data) => write!(f, "{}", hex::encode(data)),
+            Op::Num(number) => write!(f, "OP_{}", number),
+            Op::If => write!(f, "OP_IF"),
+            Op::Else => write!(f, "OP_ELSE"),
+            Op::EndIf => write!(f, "OP_ENDIF"),
+            Op::Drop => write!(f, "OP_DROP"),
+            Op::Dup => write!(f, "OP_DUP"),
+            Op::Sha256 => write!(f, "OP_SHA256"),
+            Op::Hash160 => write!(f, "OP_HASH160"),
+            Op::Equal => write!(f, "OP_EQUAL"),
+            Op::EqualVerify => write!(f, "OP_EQUALVERIFY"),
+            Op::Verify => write!(f, "OP_VERIFY"),
+            Op::CheckSig => write!(f, "OP_CHECKSIG"),
+            Op::CheckMultiSig => write!(f, "OP_CHECKMULTISIG"),
+            Op::CheckLockTimeVerify => write!(f, "OP_CHECKLOCKTIMEVERIFY"),
+            Op::Return => write!(f, "OP_RETURN"),
+        }
+    }
+}
+
+impl FromStr for Op {
+    type Err = ScriptError;
+
+    fn from_str(token: &str) -> Result<Self, Self::Err> {
+        let op = match token {
+            "OP_0" => Op::Push(vec![]),
+            "OP_IF" => Op::If,
+            "OP_ELSE" => Op::Else,
+            "OP_ENDIF" => Op::EndIf,
+            "OP_DROP" => Op::Drop,
+            "OP_DUP" => Op::Dup,
+            "OP_SHA256" => Op::Sha256,
+            "OP_HASH160" => Op::Hash160,
+            "OP_EQUAL" => Op::Equal,
+            "OP_EQUALVERIFY" => Op::EqualVerify,
+            "OP_VERIFY" => Op::Verify,
+            "OP_CHECKSIG" => Op::CheckSig,
+            "OP_CHECKMULTISIG" => Op::CheckMultiSig,
+            "OP_CHECKLOCKTIMEVERIFY" => Op::CheckLockTimeVerify,
+            "OP_RETURN" => Op::Return,
+            _ if token.starts_with("OP_") => match token[3..].parse::<u8>() {
+                Ok(number @ 1..=16) => Op::Num(number),
+                _ => return Err(ScriptError::UnknownOp(token.to_string())),
+            },
+            _ => {
+                Op::Push(hex::decode(token).map_err(|_| ScriptError::UnknownOp(token.to_string()))?)
+            }
+        };
+
+        Ok(op)
+    }
+}
+
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ops: Vec<String> = self.0.iter().map(|op| op.to_string()).collect();
+
+        write!(f, "{}", ops.join(" "))
+    }
+}
+
+impl FromStr for Script {
+    type Err = ScriptError;
+
+    fn from_str(asm: &str) -> Result<Self, Self::Err> {
+        let ops = asm
+            .split_whitespace()
+            .map(Op::from_str)
+            .collect::<Result<Vec<Op>, ScriptError>>()?;
+
+        Ok(Script(ops))
+    }
+}
+
+impl Serialize for Script {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Script {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let asm = String::deserialize(deserializer)?;
+
+        asm.parse().map_err(de::Error::custom)
+    }
+}
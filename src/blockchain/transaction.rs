@@ -1,10 +1,10 @@
-use std::{collections::HashMap, fmt, vec};
-use p256::{
-    ecdsa::{
-        signature::{Signature, Signer, Verifier},
-        SigningKey, VerifyingKey,
-    },
-    EncodedPoint,
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, vec,
+};
+use p256::ecdsa::{
+    signature::{Signature, Signer},
+    SigningKey, VerifyingKey,
 };
 use rand::distributions::{Alphanumeric, DistString};
 use serde::{Deserialize, Serialize};
@@ -16,12 +16,18 @@ use crate::{
 };
 
 use super::{
+    address::Address,
+    chain_params::CHAIN_PARAMS,
+    script::{verify_signature, Htlc, Op, Script, ScriptContext},
+    tx_builder::TxBuilder,
     utxo_set::UTXOSet,
     wallet::{Wallet, WalletNotFoundError},
     Blockchain,
 };
 
-const REWARD_AMOUNT: u32 = 10;
+/// Lock times below are block heights, the rest are timestamps in milliseconds.
+/// It is the usual 500000000 seconds (November 1985) taken in milliseconds.
+pub const LOCK_TIME_THRESHOLD: u64 = 500_000_000_000;
 
 #[derive(Debug, Clone)]
 pub struct NotEnoughFundsError;
@@ -34,39 +40,98 @@ impl fmt::Display for NotEnoughFundsError {
 
 impl std::error::Error for NotEnoughFundsError {}
 
+#[derive(Debug, Clone)]
+pub enum MultisigError {
+    UnknownOutput,
+    NotMultisig,
+    NotCosigner,
+    BadPubKey(usize),
+    DuplicatePubKey(usize),
+}
+
+impl fmt::Display for MultisigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MultisigError::UnknownOutput => write!(f, "Spent output is not found"),
+            MultisigError::NotMultisig => write!(f, "Spent output is not a multisig one"),
+            MultisigError::NotCosigner => write!(f, "Wallet key is not listed by any multisig input"),
+            MultisigError::BadPubKey(i) => write!(f, "Public key {} is not a valid P-256 key", i),
+            MultisigError::DuplicatePubKey(i) => write!(f, "Public key {} is listed twice", i),
+        }
+    }
+}
+
+impl std::error::Error for MultisigError {}
+
+#[derive(Debug, Clone)]
+pub enum HtlcError {
+    UnknownOutput,
+    NotHtlc,
+    BadHash,
+    BadPreimage,
+    WrongWallet,
+}
+
+impl fmt::Display for HtlcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HtlcError::UnknownOutput => write!(f, "Spent output is not found"),
+            HtlcError::NotHtlc => write!(f, "Spent output is not an HTLC one"),
+            HtlcError::BadHash => write!(f, "HTLC hash must be 32 bytes of SHA-256"),
+            HtlcError::BadPreimage => write!(f, "Preimage does not match the HTLC hash"),
+            HtlcError::WrongWallet => write!(f, "Wallet key is not the one the HTLC pays to"),
+        }
+    }
+}
+
+impl std::error::Error for HtlcError {}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXOutput {
     pub value: u32,
-    pub pub_key_hash: HashHex,
+    /// Conditions the spending input has to satisfy
+    pub script_pubkey: Script,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXInput {
     pub tx_id: HashHex,
     pub output_index: i32,
-    pub signature: HashHex,
-    pub pub_key: HashHex,
+    /// Data which satisfies `script_pubkey` of the spent output
+    pub script_sig: Script,
+    /// Age the spent output must reach before this input is valid
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relative_lock: Option<RelativeLock>,
 }
 
-impl TXOutput {
-    pub fn is_locked_with(&self, pub_key_hash: &HashHex) -> bool {
-        self.pub_key_hash.0 == pub_key_hash.0
-    }
+/// Age of an output counted from the block which confirmed it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RelativeLock {
+    Blocks(u64),
+    /// Milliseconds of median time past
+    Time(u64),
+}
 
-    pub fn lock(&mut self, address: HashHex) {
-        let mut pub_key_hash = bs58::decode(address.0)
-            .into_vec()
-            .expect("base58 decode to vec error");
-        pub_key_hash = pub_key_hash[1..(&pub_key_hash.len() - 4)].to_vec();
-        self.pub_key_hash = pub_key_hash.into();
-    }
+/// Time locks put on a built transaction
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+pub struct TxLocks {
+    #[serde(default)]
+    pub lock_time: u64,
+    #[serde(default)]
+    pub relative_lock: Option<RelativeLock>,
 }
 
-impl TXInput {
-    pub fn uses_key(&self, pub_key_hash: &HashHex) -> bool {
-        let locking_hash = Wallet::hash_pub_key(self.pub_key.0.clone());
+impl TXOutput {
+    pub fn new_p2pkh(value: u32, pub_key_hash: &HashHex) -> Self {
+        TXOutput {
+            value,
+            script_pubkey: Script::new_p2pkh(pub_key_hash),
+        }
+    }
 
-        pub_key_hash.0 == locking_hash.0
+    pub fn is_locked_with(&self, pub_key_hash: &HashHex) -> bool {
+        self.script_pubkey.p2pkh_hash().as_ref() == Some(pub_key_hash)
     }
 }
 
@@ -75,24 +140,29 @@ pub struct Transaction {
     pub id: HashHex,
     pub inputs: Vec<TXInput>,
     pub outputs: Vec<TXOutput>,
+    /// Height or timestamp (see `LOCK_TIME_THRESHOLD`) the transaction can't be mined before,
+    /// `0` for none
+    #[serde(default)]
+    pub lock_time: u64,
 }
 
 impl Transaction {
-    pub fn new(inputs: Vec<TXInput>, outputs: Vec<TXOutput>) -> Self {
-        let id = Self::calculate_hash(&inputs, &outputs).unwrap();
+    pub fn new(inputs: Vec<TXInput>, outputs: Vec<TXOutput>, lock_time: u64) -> Self {
+        let id = Self::calculate_hash(&inputs, &outputs, lock_time).unwrap();
 
         Transaction {
             id,
             inputs,
             outputs,
+            lock_time,
         }
     }
 
-    pub fn calculate_hash(inputs: &[TXInput], outputs: &[TXOutput]) -> Result<HashHex> {
+    pub fn calculate_hash(inputs: &[TXInput], outputs: &[TXOutput], lock_time: u64) -> Result<HashHex> {
         let vin_raw = serde_json::to_string(inputs)?;
         let vout_raw = serde_json::to_string(outputs)?;
 
-        let data = [vin_raw.as_bytes(), vout_raw.as_bytes()].concat();
+        let data = [vin_raw.as_bytes(), vout_raw.as_bytes(), &lock_time.to_le_bytes()].concat();
 
         let mut hasher = Sha256::new();
         hasher.update(data);
@@ -102,55 +172,217 @@ impl Transaction {
         Ok(HashHex(hash_bytes.to_vec()))
     }
 
-    pub fn new_utxo(
-        from: String,
-        to: String,
+    /// Id the transaction should have: signatures are added after the id is taken,
+    /// so unlocking scripts are cleared, except the coinbase data which is part of it
+    pub fn calculate_id(&self) -> Result<HashHex> {
+        if self.is_coinbase() {
+            return Self::calculate_hash(&self.inputs, &self.outputs, self.lock_time);
+        }
+
+        let inputs: Vec<TXInput> = self
+            .inputs
+            .iter()
+            .map(|input| TXInput {
+                tx_id: input.tx_id.clone(),
+                output_index: input.output_index,
+                script_sig: Script::default(),
+                relative_lock: input.relative_lock,
+            })
+            .collect();
+
+        Self::calculate_hash(&inputs, &self.outputs, self.lock_time)
+    }
+
+    /// Hash of the whole transaction, unlocking scripts included. Blocks commit to it
+    /// rather than to the id, so a relayed block can't get its signatures replaced.
+    pub fn full_hash(&self) -> HashHex {
+        Self::calculate_hash(&self.inputs, &self.outputs, self.lock_time).unwrap()
+    }
+
+    /// Locks `amount` to `required` signatures of `pub_keys`
+    pub fn new_multisig(
+        from: Address,
+        required: usize,
+        pub_keys: &[Vec<u8>],
+        amount: u32,
+        fee: u32,
+        locks: TxLocks,
+        blockchain: &Blockchain,
+    ) -> Result<Transaction> {
+        // A key which doesn't parse can never sign, and a repeated one lets a single signer
+        // count twice, so either would lock the coins in a way the sender didn't mean
+        let mut seen = HashSet::new();
+        for (i, key) in pub_keys.iter().enumerate() {
+            let key = VerifyingKey::from_sec1_bytes(key).map_err(|_| MultisigError::BadPubKey(i))?;
+            if !seen.insert(key.to_encoded_point(true).as_bytes().to_vec()) {
+                return Err(MultisigError::DuplicatePubKey(i).into());
+            }
+        }
+
+        let output = TXOutput {
+            value: amount,
+            script_pubkey: Script::new_multisig(required, pub_keys)?,
+        };
+
+        TxBuilder::new(&from, blockchain)
+            .output(output)
+            .fee(fee)
+            .locks(locks)
+            .build()
+    }
+
+    /// Unsigned spending of a multisig output, the change goes back to the same keys.
+    /// Cosigners add their signatures with `Blockchain::cosign_transaction`.
+    pub fn new_multisig_spend(
+        prev_tx_id: HashHex,
+        output_index: i32,
+        to: Address,
         amount: u32,
+        fee: u32,
+        locks: TxLocks,
         blockchain: &Blockchain,
     ) -> Result<Transaction> {
-        let wallet =
-            Wallet::get_by(&from, blockchain.store).expect("Wallet with this address is not found");
+        let utxo_set = UTXOSet { blockchain };
+        let prev_output = utxo_set
+            .get_output(&prev_tx_id, output_index)?
+            .ok_or(MultisigError::UnknownOutput)?;
+
+        if prev_output.script_pubkey.multisig_keys().is_none() {
+            return Err(MultisigError::NotMultisig.into());
+        }
+
+        let total = amount.checked_add(fee).ok_or(NotEnoughFundsError)?;
+        if prev_output.value < total {
+            return Err(NotEnoughFundsError.into());
+        }
+
+        let mut outputs = vec![TXOutput::new_p2pkh(amount, to.pub_key_hash())];
+        if prev_output.value > total {
+            outputs.push(TXOutput {
+                value: prev_output.value - total,
+                script_pubkey: prev_output.script_pubkey,
+            });
+        }
+
+        let input = TXInput {
+            tx_id: prev_tx_id,
+            output_index,
+            script_sig: Script::default(),
+            relative_lock: locks.relative_lock,
+        };
+
+        Ok(Transaction::new(vec![input], outputs, locks.lock_time))
+    }
+
+    /// Publishes `data` in an unspendable output
+    pub fn new_data(from: Address, data: Vec<u8>, fee: u32, blockchain: &Blockchain) -> Result<Transaction> {
+        let output = TXOutput {
+            value: 0,
+            script_pubkey: Script::new_data(data),
+        };
+
+        TxBuilder::new(&from, blockchain).output(output).fee(fee).build()
+    }
+
+    /// Locks `amount` to `htlc`
+    pub fn new_htlc(
+        from: Address,
+        htlc: &Htlc,
+        amount: u32,
+        fee: u32,
+        blockchain: &Blockchain,
+    ) -> Result<Transaction> {
+        if htlc.hash.len() != 32 {
+            return Err(HtlcError::BadHash.into());
+        }
+
+        let output = TXOutput {
+            value: amount,
+            script_pubkey: Script::new_htlc(htlc),
+        };
+
+        TxBuilder::new(&from, blockchain).output(output).fee(fee).build()
+    }
 
+    /// Takes an HTLC output to the recipient `address` wallet by revealing `preimage`
+    pub fn new_htlc_claim(
+        prev_tx_id: HashHex,
+        output_index: i32,
+        address: Address,
+        preimage: Vec<u8>,
+        fee: u32,
+        blockchain: &Blockchain,
+    ) -> Result<Transaction> {
+        Self::new_htlc_spend(prev_tx_id, output_index, &address, Some(preimage), fee, blockchain)
+    }
+
+    /// Takes an HTLC output back to the refund `address` wallet,
+    /// the transaction is locked until the HTLC timeout
+    pub fn new_htlc_refund(
+        prev_tx_id: HashHex,
+        output_index: i32,
+        address: Address,
+        fee: u32,
+        blockchain: &Blockchain,
+    ) -> Result<Transaction> {
+        Self::new_htlc_spend(prev_tx_id, output_index, &address, None, fee, blockchain)
+    }
+
+    fn new_htlc_spend(
+        prev_tx_id: HashHex,
+        output_index: i32,
+        address: &Address,
+        preimage: Option<Vec<u8>>,
+        fee: u32,
+        blockchain: &Blockchain,
+    ) -> Result<Transaction> {
+        let utxo_set = UTXOSet { blockchain };
+        let prev_output = utxo_set
+            .get_output(&prev_tx_id, output_index)?
+            .ok_or(HtlcError::UnknownOutput)?;
+
+        let htlc = prev_output.script_pubkey.htlc().ok_or(HtlcError::NotHtlc)?;
+
+        let wallet = Wallet::get_by(address, blockchain.store)?;
         let pub_key = wallet.pub_key_bytes_vec();
         let pub_key_hash = Wallet::hash_pub_key(pub_key.clone());
 
-        let utxo_set = UTXOSet { blockchain };
-        let (acc, spendable_outputs) = utxo_set
-            .find_spendable_outputs(&pub_key_hash, amount)
-            .unwrap();
+        let (payee, lock_time) = match &preimage {
+            Some(preimage) => {
+                if Sha256::digest(preimage).to_vec() != htlc.hash {
+                    return Err(HtlcError::BadPreimage.into());
+                }
+
+                (&htlc.recipient, 0)
+            }
+            None => (&htlc.refund, htlc.timeout),
+        };
 
-        if acc < amount {
-            return Err(NotEnoughFundsError).map_err(|e| e.into());
+        if pub_key_hash != *payee {
+            return Err(HtlcError::WrongWallet.into());
         }
 
-        let inputs: Vec<TXInput> = spendable_outputs
-            .iter()
-            .flat_map(|(tx_id, outputs)| {
-                outputs.iter().map(|output_index| TXInput {
-                    output_index: *output_index,
-                    tx_id: tx_id.to_owned(),
-                    signature: HashHex(vec![]),
-                    pub_key: pub_key.clone().into(),
-                })
-            })
-            .collect();
+        let value = match prev_output.value.checked_sub(fee) {
+            Some(v) if v > 0 => v,
+            _ => return Err(NotEnoughFundsError.into()),
+        };
 
-        let recipient_pub_key = Wallet::retrieve_pub_key_hash(&to)?;
+        let input = TXInput {
+            tx_id: prev_tx_id,
+            output_index,
+            script_sig: Script::default(),
+            relative_lock: None,
+        };
 
-        let outputs = vec![
-            TXOutput {
-                pub_key_hash: recipient_pub_key,
-                value: amount,
-            },
-            TXOutput {
-                pub_key_hash,
-                value: acc - amount,
-            },
-        ];
+        let mut tx = Transaction::new(vec![input], vec![TXOutput::new_p2pkh(value, &pub_key_hash)], lock_time);
 
-        let mut tx = Transaction::new(inputs, outputs);
+        let sighash = tx.signature_hash(0, &prev_output.script_pubkey);
+        let signature = wallet.private_key.sign(&sighash.0).as_bytes().to_vec();
 
-        blockchain.sign_transaction(&mut tx, &wallet.private_key);
+        tx.inputs[0].script_sig = match preimage {
+            Some(preimage) => Script::new_htlc_claim_sig(signature, pub_key, preimage),
+            None => Script::new_htlc_refund_sig(signature, pub_key),
+        };
 
         Ok(tx)
     }
@@ -165,54 +397,109 @@ impl Transaction {
             return;
         }
 
-        let mut new_tx = self.trimmed_copy();
-
-        let new_inputs = new_tx.inputs.clone();
+        let pub_key = VerifyingKey::from(private_key)
+            .to_encoded_point(false)
+            .as_bytes()
+            .to_vec();
 
-        for (index, input) in new_inputs.iter().enumerate() {
+        for index in 0..self.inputs.len() {
+            let input = &self.inputs[index];
             let prev_tx = prev_transactions.get(&input.tx_id).unwrap();
+            let script_pubkey = &prev_tx.outputs[input.output_index as usize].script_pubkey;
 
-            let inputs = &mut new_tx.inputs;
+            let sighash = self.signature_hash(index, script_pubkey);
+            let signature = private_key.sign(&sighash.0);
 
-            inputs[index].signature = vec![].into();
-            inputs[index].pub_key = prev_tx.outputs[input.output_index as usize]
-                .pub_key_hash
-                .clone();
-
-            new_tx.id = Self::calculate_hash(inputs, &new_tx.outputs).unwrap();
-
-            // Clearing value to evade side-effects
-            inputs[index].pub_key = vec![].into();
-
-            let signature = private_key.sign(&new_tx.id.0);
-
-            self.inputs[index].signature = signature.to_vec().into();
+            self.inputs[index].script_sig =
+                Script::new_p2pkh_sig(signature.as_bytes().to_vec(), pub_key.clone());
         }
     }
 
-    pub fn verify(&mut self, prev_transactions: &HashMap<HashHex, Transaction>) -> bool {
-        let mut new_tx = self.trimmed_copy();
-
-        for (index, input) in self.inputs.iter().enumerate() {
-            let prev_tx = prev_transactions.get(&input.tx_id).unwrap();
-
-            let inputs = &mut new_tx.inputs;
-
-            inputs[index].signature = vec![].into();
-            inputs[index].pub_key = prev_tx.outputs[input.output_index as usize]
-                .pub_key_hash
-                .clone();
-
-            new_tx.id = Self::calculate_hash(inputs, &new_tx.outputs).unwrap();
+    /// Adds a signature of `private_key` to every multisig input which lists its key,
+    /// keeping the signatures of other cosigners in key order
+    pub fn cosign(
+        &mut self,
+        prev_transactions: &HashMap<HashHex, Transaction>,
+        private_key: &SigningKey,
+    ) -> Result<()> {
+        let pub_key = VerifyingKey::from(private_key)
+            .to_encoded_point(false)
+            .as_bytes()
+            .to_vec();
+
+        let mut signed = false;
+
+        for index in 0..self.inputs.len() {
+            let input = &self.inputs[index];
+            let script_pubkey = &prev_transactions
+                .get(&input.tx_id)
+                .and_then(|prev_tx| prev_tx.outputs.get(input.output_index as usize))
+                .ok_or(MultisigError::UnknownOutput)?
+                .script_pubkey;
+
+            let (required, pub_keys) = match script_pubkey.multisig_keys() {
+                Some(v) if v.1.contains(&pub_key) => v,
+                _ => continue,
+            };
+
+            let sighash = self.signature_hash(index, script_pubkey);
+            let signature = private_key.sign(&sighash.0).as_bytes().to_vec();
+
+            let present: Vec<Vec<u8>> = input
+                .script_sig
+                .0
+                .iter()
+                .filter_map(|op| match op {
+                    Op::Push(data) => Some(data.clone()),
+                    _ => None,
+                })
+                .collect();
+
+            let signatures: Vec<Op> = pub_keys
+                .iter()
+                .filter_map(|key| {
+                    if *key == pub_key {
+                        return Some(signature.clone());
+                    }
+
+                    present
+                        .iter()
+                        .find(|present| verify_signature(&sighash, present, key))
+                        .cloned()
+                })
+                .take(required)
+                .map(Op::Push)
+                .collect();
 
-            inputs[index].pub_key = vec![].into();
+            self.inputs[index].script_sig = Script(signatures);
+            signed = true;
+        }
 
-            let encoded_point = EncodedPoint::from_bytes(&input.pub_key.0).unwrap();
-            let verify_key = VerifyingKey::from_encoded_point(&encoded_point).unwrap();
+        if !signed {
+            return Err(MultisigError::NotCosigner.into());
+        }
 
-            let signature = p256::ecdsa::Signature::from_bytes(&input.signature.0).unwrap();
+        Ok(())
+    }
 
-            if verify_key.verify(&new_tx.id.0, &signature).is_err() {
+    /// Runs every input script against the output it spends
+    pub fn verify(&self, prev_transactions: &HashMap<HashHex, Transaction>) -> bool {
+        for (index, input) in self.inputs.iter().enumerate() {
+            let script_pubkey = match prev_transactions
+                .get(&input.tx_id)
+                .and_then(|prev_tx| prev_tx.outputs.get(input.output_index as usize))
+            {
+                Some(output) => &output.script_pubkey,
+                None => return false,
+            };
+
+            let context = ScriptContext {
+                sighash: self.signature_hash(index, script_pubkey),
+                lock_time: self.lock_time,
+            };
+
+            if let Err(e) = Script::verify(&input.script_sig, script_pubkey, &context) {
+                println!("[!] Input {} of {} is not valid: {}", index, hex::encode(&self.id.0), e);
                 return false;
             }
         }
@@ -220,33 +507,55 @@ impl Transaction {
         true
     }
 
+    /// Hash signed by the input at `index`: the transaction with all unlocking scripts
+    /// cleared and the signed one replaced by `script_pubkey` of the spent output
+    pub fn signature_hash(&self, index: usize, script_pubkey: &Script) -> HashHex {
+        let mut inputs: Vec<TXInput> = self
+            .inputs
+            .iter()
+            .map(|input| TXInput {
+                tx_id: input.tx_id.clone(),
+                output_index: input.output_index,
+                script_sig: Script::default(),
+                relative_lock: input.relative_lock,
+            })
+            .collect();
+
+        inputs[index].script_sig = script_pubkey.clone();
+
+        Self::calculate_hash(&inputs, &self.outputs, self.lock_time).unwrap()
+    }
+
+    /// Pays the subsidy of the block at `height` plus `fees` collected from its transactions
     pub fn new_coinbase(
-        address: String,
+        address: Address,
         signature: Option<String>,
+        height: u64,
+        fees: u32,
         store: &AppStore,
     ) -> Result<Self> {
-        let wallet = match Wallet::get_by(&address, store) {
-            Some(v) => v,
-            None => return Err(WalletNotFoundError).map_err(|e| e.into()),
-        };
+        // Only the address is needed, so a locked wallet still gets the reward
+        if !Wallet::contains(&address, store)? {
+            return Err(WalletNotFoundError.into());
+        }
 
-        let pub_key = wallet.pub_key_bytes_vec();
-        let pub_key_hash = Wallet::hash_pub_key(pub_key.clone());
+        let pub_key_hash = address.pub_key_hash();
 
         let signature = signature.unwrap_or_else(|| Alphanumeric.sample_string(&mut rand::thread_rng(), 20));
 
+        // Height goes first, so coinbase ids of different blocks never collide
+        let mut coinbase_data = height.to_le_bytes().to_vec();
+        coinbase_data.extend_from_slice(signature.as_bytes());
+
         let tx_in = TXInput {
             tx_id: HashHex(vec![]),
             output_index: -1,
-            pub_key: HashHex(pub_key),
-            signature: signature.as_bytes().into(),
-        };
-        let tx_out = TXOutput {
-            value: REWARD_AMOUNT,
-            pub_key_hash,
+            script_sig: Script(vec![Op::Push(coinbase_data)]),
+            relative_lock: None,
         };
+        let tx_out = TXOutput::new_p2pkh(CHAIN_PARAMS.block_subsidy(height) + fees, pub_key_hash);
 
-        Ok(Transaction::new(vec![tx_in], vec![tx_out]))
+        Ok(Transaction::new(vec![tx_in], vec![tx_out], 0))
     }
 
     pub fn is_coinbase(&self) -> bool {
@@ -255,24 +564,31 @@ impl Transaction {
             && self.inputs[0].output_index == -1
     }
 
-    fn trimmed_copy(&self) -> Self {
-        let inputs: Vec<TXInput> = self
-            .inputs
-            .iter()
-            .map(|input| TXInput {
-                tx_id: input.tx_id.clone(),
-                output_index: input.output_index,
-                pub_key: vec![].into(),
-                signature: vec![].into(),
-            })
-            .collect();
+    /// Whether `lock_time` allows the transaction into a block at `height`
+    /// whose median time past is `median_time`
+    pub fn is_final(&self, height: u64, median_time: u64) -> bool {
+        if self.lock_time == 0 {
+            return true;
+        }
 
-        let outputs: Vec<TXOutput> = self.outputs.clone();
+        if self.lock_time < LOCK_TIME_THRESHOLD {
+            self.lock_time <= height
+        } else {
+            self.lock_time <= median_time
+        }
+    }
 
-        Transaction {
-            id: self.id.clone(),
-            inputs,
-            outputs,
+    /// Block height committed by the coinbase input
+    pub fn coinbase_height(&self) -> Option<u64> {
+        if !self.is_coinbase() {
+            return None;
         }
+
+        let height = match self.inputs[0].script_sig.0.first()? {
+            Op::Push(data) => data.get(..8)?,
+            _ => return None,
+        };
+
+        Some(u64::from_le_bytes(height.try_into().ok()?))
     }
 }
@@ -0,0 +1,114 @@
+use crate::utils::Result;
+
+use super::{
+    address::Address,
+    coin_selection::CoinSelection,
+    script::Script,
+    transaction::{NotEnoughFundsError, TXInput, TXOutput, Transaction, TxLocks},
+    utxo_set::UTXOSet,
+    wallet::Wallet,
+    Blockchain,
+};
+
+/// Collects outputs of a transaction paid by the `from` wallet. `build` picks its
+/// p2pkh coins with the chosen strategy, returns the rest as change and signs the inputs.
+pub struct TxBuilder<'a> {
+    blockchain: &'a Blockchain<'a>,
+    from: Address,
+    outputs: Vec<TXOutput>,
+    fee: u32,
+    locks: TxLocks,
+    coin_selection: CoinSelection,
+}
+
+impl<'a> TxBuilder<'a> {
+    pub fn new(from: &Address, blockchain: &'a Blockchain<'a>) -> Self {
+        TxBuilder {
+            blockchain,
+            from: from.clone(),
+            outputs: vec![],
+            fee: 0,
+            locks: TxLocks::default(),
+            coin_selection: CoinSelection::default(),
+        }
+    }
+
+    /// Pays `amount` to the wallet `address`
+    pub fn pay_to(self, address: &Address, amount: u32) -> Self {
+        self.output(TXOutput::new_p2pkh(amount, address.pub_key_hash()))
+    }
+
+    pub fn output(mut self, output: TXOutput) -> Self {
+        self.outputs.push(output);
+        self
+    }
+
+    /// Part of inputs value which is not returned as change
+    pub fn fee(mut self, fee: u32) -> Self {
+        self.fee = fee;
+        self
+    }
+
+    /// Time locks of the transaction, the relative one is put on every input
+    pub fn locks(mut self, locks: TxLocks) -> Self {
+        self.locks = locks;
+        self
+    }
+
+    pub fn coin_selection(mut self, coin_selection: CoinSelection) -> Self {
+        self.coin_selection = coin_selection;
+        self
+    }
+
+    pub fn build(self) -> Result<Transaction> {
+        let TxBuilder {
+            blockchain,
+            from,
+            mut outputs,
+            fee,
+            locks,
+            coin_selection,
+        } = self;
+
+        let wallet = Wallet::get_by(&from, blockchain.store)?;
+
+        let pub_key_hash = Wallet::hash_pub_key(wallet.pub_key_bytes_vec());
+
+        let total = outputs
+            .iter()
+            .try_fold(fee, |acc, output| acc.checked_add(output.value))
+            .ok_or(NotEnoughFundsError)?;
+
+        let utxo_set = UTXOSet { blockchain };
+        let coins = utxo_set.find_spendable_coins(&pub_key_hash)?;
+
+        // Every transaction spends at least one coin, even a zero valued one
+        let selected = coin_selection
+            .selector()
+            .select(&coins, (total as u64).max(1))
+            .ok_or(NotEnoughFundsError)?;
+
+        let acc: u64 = selected.iter().map(|coin| coin.value as u64).sum();
+        let change = u32::try_from(acc - total as u64)?;
+
+        let inputs: Vec<TXInput> = selected
+            .into_iter()
+            .map(|coin| TXInput {
+                output_index: coin.output_index,
+                tx_id: coin.tx_id,
+                script_sig: Script::default(),
+                relative_lock: locks.relative_lock,
+            })
+            .collect();
+
+        if change > 0 {
+            outputs.push(TXOutput::new_p2pkh(change, &pub_key_hash));
+        }
+
+        let mut tx = Transaction::new(inputs, outputs, locks.lock_time);
+
+        blockchain.sign_transaction(&mut tx, &wallet.private_key)?;
+
+        Ok(tx)
+    }
+}
@@ -0,0 +1,107 @@
+use kv::{Batch, Json};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{HashHex, Result};
+
+use super::{block::Block, transaction::Transaction, Blockchain};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TxLocation {
+    pub block_hash: HashHex,
+    pub position: u32,
+}
+
+/// Optional txid -> block location index. It is enabled once built with `reindex`,
+/// after that `Blockchain::add_block` keeps it up to date.
+pub struct TxIndex<'a> {
+    pub blockchain: &'a Blockchain<'a>,
+}
+
+impl<'a> TxIndex<'a> {
+    pub fn is_enabled(&self) -> bool {
+        self.blockchain.store.has_txindex()
+    }
+
+    pub fn get(&self, tx_id: &HashHex) -> Result<Option<TxLocation>> {
+        let bucket = self.blockchain.store.get_txindex_bucket()?;
+
+        let location = bucket.get(tx_id.to_vec())?.map(|v| v.0);
+
+        Ok(location)
+    }
+
+    pub fn find_transaction(&self, tx_id: &HashHex) -> Result<Option<Transaction>> {
+        let location = match self.get(tx_id)? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+
+        let block = self
+            .blockchain
+            .get_block(&location.block_hash)?
+            .ok_or("Indexed block is not found")?;
+
+        Ok(block.transactions.get(location.position as usize).cloned())
+    }
+
+    pub fn index_block(&self, block: &Block) -> Result<()> {
+        let bucket = self.blockchain.store.get_txindex_bucket()?;
+
+        bucket.batch(Self::block_batch(block)?)?;
+
+        Ok(())
+    }
+
+    pub fn deindex_block(&self, block: &Block) -> Result<()> {
+        let bucket = self.blockchain.store.get_txindex_bucket()?;
+
+        let mut batch = Batch::new();
+        for tx in block.transactions.iter() {
+            batch.remove(tx.id.to_vec())?;
+        }
+
+        bucket.batch(batch)?;
+
+        Ok(())
+    }
+
+    pub fn reindex(&self) -> Result<()> {
+        println!("-> Transaction index rebuilding...");
+
+        // Lookups scan the chain until the build is done, so a failure part-way can't
+        // leave a partial index behind which is taken for a complete one
+        let store = self.blockchain.store;
+        store.start_txindex_build()?;
+
+        let bucket = store.get_txindex_bucket()?;
+        bucket.clear()?;
+
+        let height = self.blockchain.height()?;
+
+        for block in self.blockchain.get_blocks_range(0, height)? {
+            bucket.batch(Self::block_batch(&block)?)?;
+        }
+
+        bucket.flush()?;
+        store.finish_txindex_build()?;
+
+        println!("-> Transaction index rebuilt up to height {}", height);
+
+        Ok(())
+    }
+
+    fn block_batch(block: &Block) -> Result<Batch<Vec<u8>, Json<TxLocation>>> {
+        let mut batch = Batch::new();
+
+        for (position, tx) in block.transactions.iter().enumerate() {
+            let location = TxLocation {
+                block_hash: block.hash.clone(),
+                position: position as u32,
+            };
+
+            batch.set(tx.id.to_vec(), Json(location))?;
+        }
+
+        Ok(batch)
+    }
+}
@@ -1,23 +1,46 @@
 use std::collections::HashMap;
 
-use kv::{Batch, Json};
+use kv::Json;
+use serde::{Deserialize, Serialize};
 
 use crate::utils::{HashHex, Result};
 
-use super::{block::Block, transaction::TXOutput, Blockchain};
+use super::{block::Block, mempool::Mempool, transaction::TXOutput, Blockchain};
 
 pub struct UTXOSet<'a> {
     pub blockchain: &'a Blockchain<'a>,
 }
 
-pub type Accumulated = u32;
+/// Unspent output a wallet can spend
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coin {
+    pub tx_id: HashHex,
+    pub output_index: i32,
+    pub value: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpentOutput {
+    pub tx_id: HashHex,
+    pub output_index: i32,
+    pub output: TXOutput,
+}
+
+/// Outputs spent by a connected block, in spending order
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BlockUndo {
+    pub spent: Vec<SpentOutput>,
+}
 
 impl<'a> UTXOSet<'a> {
-    // TODO: Неправильно работает апдейт - у коинбейз тразакций одинаковый айди, поэтому данные перезаписываются
+    /// Connects `block` to the chainstate. Outputs it spends are kept as the undo
+    /// record of the block, so `disconnect` can bring them back.
     pub fn update(&self, block: &Block) -> Result<()> {
         let bucket = self.blockchain.store.get_chainstate_bucket()?;
 
-        bucket.transaction(|tx| {
+        let spent = bucket.transaction(|tx| {
+            let mut spent = vec![];
+
             for bc_tx in block.transactions.iter() {
                 if !bc_tx.is_coinbase() {
                     for input in bc_tx.inputs.iter() {
@@ -29,6 +52,14 @@ impl<'a> UTXOSet<'a> {
                             None => continue,
                         };
 
+                        if let Some(output) = stored_outputs.get(&input.output_index) {
+                            spent.push(SpentOutput {
+                                tx_id: input.tx_id.clone(),
+                                output_index: input.output_index,
+                                output: output.clone(),
+                            });
+                        }
+
                         let filtered: HashMap<i32, TXOutput> = stored_outputs
                             .iter()
                             .filter_map(|(index, out)| {
@@ -48,53 +79,81 @@ impl<'a> UTXOSet<'a> {
                 }
 
                 let tx_id = bc_tx.id.to_owned().to_vec();
+                // Data outputs can't be spent, there is no need to keep them
                 let outputs: HashMap<i32, TXOutput> = bc_tx
                     .outputs
                     .iter()
                     .cloned()
                     .enumerate()
+                    .filter(|(_, out)| !out.script_pubkey.is_unspendable())
                     .map(|(index, out)| (index as i32, out))
                     .collect();
-                let outputs = Json(outputs);
 
-                tx.set(tx_id, outputs)?;
+                if !outputs.is_empty() {
+                    tx.set(tx_id, Json(outputs))?;
+                }
+            }
+
+            Ok(spent)
+        })?;
+
+        let undo_bucket = self.blockchain.store.get_undo_bucket()?;
+        undo_bucket.set(block.hash.to_vec(), Json(BlockUndo { spent }))?;
+
+        Ok(())
+    }
+
+    /// Reverts `update` of the tip `block`: the outputs it spent are restored from
+    /// its undo record, then its own outputs are removed
+    pub fn disconnect(&self, block: &Block) -> Result<()> {
+        let undo_bucket = self.blockchain.store.get_undo_bucket()?;
+
+        let undo = undo_bucket
+            .get(block.hash.to_vec())?
+            .ok_or("Block undo data is not found")?
+            .0;
+
+        let bucket = self.blockchain.store.get_chainstate_bucket()?;
+
+        bucket.transaction(|tx| {
+            for spent in undo.spent.iter() {
+                let tx_id = spent.tx_id.to_vec();
+
+                let mut outputs = tx.get(tx_id.clone())?.map(|v| v.0).unwrap_or_default();
+                outputs.insert(spent.output_index, spent.output.clone());
+
+                tx.set(tx_id, Json(outputs))?;
+            }
+
+            for bc_tx in block.transactions.iter() {
+                tx.remove(bc_tx.id.to_vec())?;
             }
 
             Ok(())
         })?;
 
+        undo_bucket.remove(block.hash.to_vec())?;
+
         Ok(())
     }
 
+    /// Rebuilds the chainstate and undo records by connecting main chain blocks
+    /// from the genesis one
     pub fn reindex(&self) -> Result<()> {
         println!("-> Chainstate reindex begining...");
 
         let bucket = self.blockchain.store.get_chainstate_bucket()?;
+        let undo_bucket = self.blockchain.store.get_undo_bucket()?;
 
         bucket.clear()?;
+        undo_bucket.clear()?;
         println!("[!] Chainstate store cleared");
 
-        let bc = self.blockchain.clone();
-        let mut batch = Batch::new();
-
-        for block in bc {
-            for bc_tx in block.transactions.iter() {
-                let tx_id = bc_tx.id.clone();
-                let outputs: HashMap<i32, TXOutput> = bc_tx
-                    .outputs
-                    .iter()
-                    .cloned()
-                    .enumerate()
-                    .map(|(index, out)| (index as i32, out))
-                    .collect();
-                let outputs = Json(outputs);
-
-                batch.set(tx_id.to_vec(), outputs)?;
-            }
+        let height = self.blockchain.height()?;
+        for block in self.blockchain.get_blocks_range(0, height)? {
+            self.update(&block)?;
         }
 
-        bucket.batch(batch)?;
-
         println!("-> Chainstate reindex completed!");
 
         Ok(())
@@ -128,37 +187,69 @@ impl<'a> UTXOSet<'a> {
         Ok(outputs)
     }
 
-    pub fn find_spendable_outputs(
-        &self,
-        pub_key_hash: &HashHex,
-        amount: u32,
-    ) -> Result<(Accumulated, HashMap<HashHex, Vec<i32>>)> {
+    /// Value of all unspent outputs
+    pub fn total_value(&self) -> Result<u64> {
         let bucket = self.blockchain.store.get_chainstate_bucket()?;
 
-        let mut unspent_outputs = HashMap::<HashHex, Vec<i32>>::new();
-        let mut accumulated = 0;
+        let mut total = 0_u64;
+        for item in bucket.iter() {
+            let outputs = item?.value::<Json<HashMap<i32, TXOutput>>>()?.0;
+
+            total += outputs.values().map(|out| out.value as u64).sum::<u64>();
+        }
+
+        Ok(total)
+    }
+
+    /// Whether the transaction `tx_id` still has unspent outputs
+    pub fn has_outputs(&self, tx_id: &HashHex) -> Result<bool> {
+        let bucket = self.blockchain.store.get_chainstate_bucket()?;
+
+        Ok(bucket.contains(tx_id.to_vec())?)
+    }
+
+    pub fn get_output(&self, tx_id: &HashHex, output_index: i32) -> Result<Option<TXOutput>> {
+        let bucket = self.blockchain.store.get_chainstate_bucket()?;
+
+        let output = bucket
+            .get(tx_id.to_vec())?
+            .and_then(|outputs| outputs.0.get(&output_index).cloned());
+
+        Ok(output)
+    }
+
+    /// Unspent outputs locked to `pub_key_hash` which no queued transaction spends yet
+    pub fn find_spendable_coins(&self, pub_key_hash: &HashHex) -> Result<Vec<Coin>> {
+        let bucket = self.blockchain.store.get_chainstate_bucket()?;
+
+        // Outputs already claimed by queued transactions can't be spent twice
+        let reserved = Mempool {
+            blockchain: self.blockchain,
+        }
+        .spent_outputs()?;
+
+        let mut coins = Vec::new();
 
         for item in bucket.iter() {
             let item = item?;
-            let tx_id: Vec<u8> = item.key()?;
+            let tx_id: HashHex = item.key::<Vec<u8>>()?.into();
             let outputs = item.value::<Json<HashMap<i32, TXOutput>>>()?.0;
 
             for (output_index, output) in outputs {
-                if output.is_locked_with(pub_key_hash) && accumulated < amount {
-                    accumulated += output.value;
-
-                    unspent_outputs
-                        .entry(tx_id.clone().into())
-                        .or_insert_with(Vec::new)
-                        .push(output_index as i32);
+                if reserved.contains(&(tx_id.clone(), output_index)) {
+                    continue;
+                }
 
-                    if accumulated >= amount {
-                        break;
-                    }
+                if output.is_locked_with(pub_key_hash) {
+                    coins.push(Coin {
+                        tx_id: tx_id.clone(),
+                        output_index,
+                        value: output.value,
+                    });
                 }
             }
         }
 
-        Ok((accumulated, unspent_outputs))
+        Ok(coins)
     }
 }
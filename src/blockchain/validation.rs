@@ -0,0 +1,386 @@
+use std::{collections::HashSet, fmt};
+
+use crate::utils::{get_current_time, HashHex, Result};
+
+use super::{
+    block::{Block, BlockHeader},
+    chain_params::CHAIN_PARAMS,
+    mempool::Outpoint,
+    proof_of_work::ProofOfWork,
+    transaction::{RelativeLock, Transaction},
+    utxo_set::UTXOSet,
+    Blockchain,
+};
+
+#[derive(Debug, Clone)]
+pub enum ValidationError {
+    BadHash,
+    BadProofOfWork,
+    BadDifficulty,
+    BadMerkleRoot,
+    BadTransactionId,
+    EmptyTransaction,
+    BadHeight,
+    TimestampTooOld,
+    TimestampTooFar,
+    NoTransactions,
+    BadCoinbase,
+    BadCoinbaseReward,
+    BadCoinbaseHeight,
+    DuplicateTransaction,
+    OverwritesUnspent,
+    MissingInput,
+    DoubleSpend,
+    ValueOverflow,
+    InsufficientInputValue,
+    BadSignature,
+    NotFinal,
+    RelativeLocked,
+    DataTooLarge,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            ValidationError::BadHash => "Block hash does not match its header",
+            ValidationError::BadProofOfWork => "Block proof-of-work validation error",
+            ValidationError::BadDifficulty => {
+                "Block difficulty bits do not match the retarget rules"
+            }
+            ValidationError::BadMerkleRoot => "Block merkle root does not match its transactions",
+            ValidationError::BadTransactionId => "Transaction id does not match its contents",
+            ValidationError::EmptyTransaction => "Transaction has no inputs or no outputs",
+            ValidationError::BadHeight => "Block height does not follow its parent",
+            ValidationError::TimestampTooOld => {
+                "Block timestamp is not after the median time of previous blocks"
+            }
+            ValidationError::TimestampTooFar => "Block timestamp is too far in the future",
+            ValidationError::NoTransactions => "Block has no transactions",
+            ValidationError::BadCoinbase => {
+                "Block must have exactly one coinbase transaction at first position"
+            }
+            ValidationError::BadCoinbaseReward => "Coinbase transaction claims a wrong reward",
+            ValidationError::BadCoinbaseHeight => {
+                "Coinbase transaction does not commit to the block height"
+            }
+            ValidationError::DuplicateTransaction => "Block contains a transaction twice",
+            ValidationError::OverwritesUnspent => {
+                "Transaction id is taken by a transaction with unspent outputs"
+            }
+            ValidationError::MissingInput => "Transaction spends an unknown or spent output",
+            ValidationError::DoubleSpend => "Output is spent twice",
+            ValidationError::ValueOverflow => "Transaction value overflow",
+            ValidationError::InsufficientInputValue => {
+                "Transaction outputs value is greater than inputs value"
+            }
+            ValidationError::BadSignature => "Transaction verifying error",
+            ValidationError::NotFinal => "Transaction lock time is not reached yet",
+            ValidationError::RelativeLocked => {
+                "Transaction spends an output before its relative lock is over"
+            }
+            ValidationError::DataTooLarge => "Data output carries too many bytes",
+        };
+
+        write!(f, "{}", message)
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Height and median time past of the block a transaction goes into,
+/// time locks are checked against them
+#[derive(Debug, Clone, Copy)]
+pub struct LockContext {
+    pub height: u64,
+    pub median_time: u64,
+}
+
+/// Consensus rules for blocks connecting to the current tip
+pub struct BlockValidator<'a> {
+    pub blockchain: &'a Blockchain<'a>,
+}
+
+impl<'a> BlockValidator<'a> {
+    pub fn validate(&self, block: &Block) -> Result<()> {
+        self.validate_header(&block.header, &block.hash)?;
+
+        let height = self.blockchain.block_height(&block.header.prev_hash)? + 1;
+        if block.height != height {
+            return Err(ValidationError::BadHeight.into());
+        }
+
+        Self::check_body(block)?;
+
+        let (coinbase, transactions) = block
+            .transactions
+            .split_first()
+            .ok_or(ValidationError::NoTransactions)?;
+
+        // Chainstate is keyed by txid, so an unspent transaction must not be overwritten
+        let utxo_set = UTXOSet {
+            blockchain: self.blockchain,
+        };
+        for tx in block.transactions.iter() {
+            if utxo_set.has_outputs(&tx.id)? {
+                return Err(ValidationError::OverwritesUnspent.into());
+            }
+        }
+
+        let context = LockContext {
+            height,
+            median_time: self.blockchain.median_time_past(&block.header.prev_hash)?,
+        };
+
+        let mut spent = HashSet::<Outpoint>::new();
+        let mut fees = 0_u64;
+        for tx in transactions.iter() {
+            fees += self.check_transaction(tx, &mut spent, &context)?;
+        }
+
+        // Miner may claim less than allowed, but never more
+        let subsidy = CHAIN_PARAMS.block_subsidy(height) as u64;
+
+        let reward = Self::sum_outputs(coinbase)?;
+        if reward > subsidy + fees {
+            return Err(ValidationError::BadCoinbaseReward.into());
+        }
+
+        Ok(())
+    }
+
+    /// Context-free checks of the block transactions. The merkle root goes first, so a
+    /// body which is not the one of the header fails on it and nothing else.
+    pub fn check_body(block: &Block) -> Result<()> {
+        let (coinbase, transactions) = block
+            .transactions
+            .split_first()
+            .ok_or(ValidationError::NoTransactions)?;
+
+        if Block::hash_transactions(&block.transactions).as_ref() != Some(&block.header.merkle_root)
+        {
+            return Err(ValidationError::BadMerkleRoot.into());
+        }
+
+        if !coinbase.is_coinbase() || transactions.iter().any(|tx| tx.is_coinbase()) {
+            return Err(ValidationError::BadCoinbase.into());
+        }
+
+        if coinbase.coinbase_height() != Some(block.height) {
+            return Err(ValidationError::BadCoinbaseHeight.into());
+        }
+
+        for tx in block.transactions.iter() {
+            Self::check_id(tx)?;
+        }
+
+        let mut tx_ids = HashSet::new();
+        if !block.transactions.iter().all(|tx| tx_ids.insert(&tx.id)) {
+            return Err(ValidationError::DuplicateTransaction.into());
+        }
+
+        if transactions
+            .iter()
+            .any(|tx| tx.inputs.is_empty() || tx.outputs.is_empty())
+        {
+            return Err(ValidationError::EmptyTransaction.into());
+        }
+
+        Ok(())
+    }
+
+    /// Context-free checks of a genesis block received from another node
+    pub fn validate_genesis(block: &Block) -> Result<()> {
+        let header = &block.header;
+
+        if header.hash() != block.hash {
+            return Err(ValidationError::BadHash.into());
+        }
+
+        if !header.prev_hash.0.is_empty() || block.height != 0 {
+            return Err(ValidationError::BadHeight.into());
+        }
+
+        if header.bits != CHAIN_PARAMS.genesis_bits {
+            return Err(ValidationError::BadDifficulty.into());
+        }
+
+        if !ProofOfWork::new(header).validate() {
+            return Err(ValidationError::BadProofOfWork.into());
+        }
+
+        // There is nothing to spend yet, so the coinbase is the only transaction
+        let coinbase = match block.transactions.as_slice() {
+            [coinbase] => coinbase,
+            _ => return Err(ValidationError::BadCoinbase.into()),
+        };
+
+        Self::check_body(block)?;
+
+        if Self::sum_outputs(coinbase)? > CHAIN_PARAMS.block_subsidy(0) as u64 {
+            return Err(ValidationError::BadCoinbaseReward.into());
+        }
+
+        Ok(())
+    }
+
+    /// Ids are not trusted, other checks and the chainstate are keyed by them
+    pub fn check_id(tx: &Transaction) -> Result<()> {
+        if tx.calculate_id()? != tx.id {
+            return Err(ValidationError::BadTransactionId.into());
+        }
+
+        Ok(())
+    }
+
+    /// Context checks of a header whose parent is already stored
+    pub fn validate_header(&self, header: &BlockHeader, hash: &HashHex) -> Result<()> {
+        if header.hash() != *hash {
+            return Err(ValidationError::BadHash.into());
+        }
+
+        if !ProofOfWork::new(header).validate() {
+            return Err(ValidationError::BadProofOfWork.into());
+        }
+
+        let parent = self
+            .blockchain
+            .get_block_index(&header.prev_hash)?
+            .ok_or("Parent block is not found")?;
+
+        if header.bits
+            != self
+                .blockchain
+                .expected_bits(&parent.header, parent.height + 1)?
+        {
+            return Err(ValidationError::BadDifficulty.into());
+        }
+
+        if header.timestamp <= self.blockchain.median_time_past(&header.prev_hash)? {
+            return Err(ValidationError::TimestampTooOld.into());
+        }
+
+        if header.timestamp > get_current_time() + CHAIN_PARAMS.max_future_block_time {
+            return Err(ValidationError::TimestampTooFar.into());
+        }
+
+        Ok(())
+    }
+
+    /// Lock context of the block which goes on top of the current tip
+    pub fn next_block_context(&self) -> Result<LockContext> {
+        Ok(LockContext {
+            height: self.blockchain.height()? + 1,
+            median_time: self.blockchain.median_time_past(&self.blockchain.tip)?,
+        })
+    }
+
+    /// Checks a non-coinbase transaction against the UTXO set. Outputs it spends are
+    /// added into `spent`, so the same set catches double spends between transactions.
+    /// Returns the transaction fee.
+    pub fn check_transaction(
+        &self,
+        tx: &Transaction,
+        spent: &mut HashSet<Outpoint>,
+        context: &LockContext,
+    ) -> Result<u64> {
+        if tx.is_coinbase() {
+            return Err(ValidationError::BadCoinbase.into());
+        }
+
+        // Every transaction spends at least one coin into at least one output
+        if tx.inputs.is_empty() || tx.outputs.is_empty() {
+            return Err(ValidationError::EmptyTransaction.into());
+        }
+
+        if !tx.is_final(context.height, context.median_time) {
+            return Err(ValidationError::NotFinal.into());
+        }
+
+        let utxo_set = UTXOSet {
+            blockchain: self.blockchain,
+        };
+
+        let mut input_value = 0_u64;
+        for input in tx.inputs.iter() {
+            let output = utxo_set
+                .get_output(&input.tx_id, input.output_index)?
+                .ok_or(ValidationError::MissingInput)?;
+
+            if !spent.insert((input.tx_id.clone(), input.output_index)) {
+                return Err(ValidationError::DoubleSpend.into());
+            }
+
+            if let Some(lock) = input.relative_lock {
+                self.check_relative_lock(&input.tx_id, lock, context)?;
+            }
+
+            input_value += output.value as u64;
+        }
+
+        if tx.outputs.iter().any(|output| {
+            output.script_pubkey.is_unspendable()
+                && output.script_pubkey.data_size() > CHAIN_PARAMS.max_data_size
+        }) {
+            return Err(ValidationError::DataTooLarge.into());
+        }
+
+        let output_value = Self::sum_outputs(tx)?;
+        if input_value < output_value {
+            return Err(ValidationError::InsufficientInputValue.into());
+        }
+
+        if !self.blockchain.verify_transaction(&mut tx.clone())? {
+            return Err(ValidationError::BadSignature.into());
+        }
+
+        Ok(input_value - output_value)
+    }
+
+    fn check_relative_lock(
+        &self,
+        tx_id: &HashHex,
+        lock: RelativeLock,
+        context: &LockContext,
+    ) -> Result<()> {
+        let confirmed_at = self
+            .blockchain
+            .find_transaction_height(tx_id)?
+            .ok_or(ValidationError::MissingInput)?;
+
+        let is_unlocked = match lock {
+            RelativeLock::Blocks(blocks) => context.height >= confirmed_at.saturating_add(blocks),
+            RelativeLock::Time(time) => {
+                // Output age is counted from the median time past before its block
+                let confirmed_time = match confirmed_at.checked_sub(1) {
+                    Some(height) => {
+                        let hash = self
+                            .blockchain
+                            .get_hash_by_height(height)?
+                            .ok_or("Block is not found")?;
+
+                        self.blockchain.median_time_past(&hash)?
+                    }
+                    None => 0,
+                };
+
+                context.median_time >= confirmed_time.saturating_add(time)
+            }
+        };
+
+        if !is_unlocked {
+            return Err(ValidationError::RelativeLocked.into());
+        }
+
+        Ok(())
+    }
+
+    fn sum_outputs(tx: &Transaction) -> Result<u64> {
+        let value = tx
+            .outputs
+            .iter()
+            .try_fold(0_u32, |acc, out| acc.checked_add(out.value))
+            .ok_or(ValidationError::ValueOverflow)?;
+
+        Ok(value as u64)
+    }
+}
@@ -3,17 +3,24 @@ use std::{
     sync::{Arc, Mutex},
 };
 
+use kv::{Batch, Bucket};
 use p256::ecdsa::{SigningKey, VerifyingKey};
 use rand_core::OsRng;
-use ripemd::Ripemd160;
-use sha2::{Digest, Sha256};
 
 use crate::{
     store::AppStore,
     utils::{HashHex, Result},
 };
 
-const VERSION: u16 = 1;
+use super::{
+    address::{checksum, Address, AddressError},
+    hd_wallet::HdKeychain,
+    keystore::Keystore,
+    script::hash160,
+};
+
+/// Leading byte of exported private keys
+const PRIVATE_KEY_VERSION: u8 = 0x80;
 
 #[derive(Debug, Clone)]
 pub struct WalletNotFoundError;
@@ -26,6 +33,17 @@ impl fmt::Display for WalletNotFoundError {
 
 impl std::error::Error for WalletNotFoundError {}
 
+#[derive(Debug, Clone)]
+pub struct BadPrivateKeyError;
+
+impl fmt::Display for BadPrivateKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Private key is not a valid exported key")
+    }
+}
+
+impl std::error::Error for BadPrivateKeyError {}
+
 #[derive(Debug, Clone)]
 pub struct Wallet {
     pub private_key: SigningKey,
@@ -43,113 +61,164 @@ impl Wallet {
         }
     }
 
-    pub fn create(store: Arc<Mutex<AppStore>>) -> Result<HashHex> {
-        let wallet = Wallet::new();
-        let address = wallet.generate_address();
-
+    /// Next wallet of the HD keychain when there is one, a standalone random one otherwise
+    pub fn create(store: Arc<Mutex<AppStore>>) -> Result<Address> {
         let store = store.lock().unwrap();
-        let wallets = store.get_wallets_bucket()?;
 
-        wallets.set(address.0.as_slice(), wallet.private_key.to_bytes().to_vec())?;
+        let wallet = match HdKeychain::load(&store)? {
+            Some(mut keychain) => {
+                let wallet = keychain.next_wallet()?;
+                keychain.save(&store)?;
+
+                wallet
+            }
+            None => Wallet::new(),
+        };
+
+        wallet.save(&store)
+    }
+
+    pub fn save(&self, store: &AppStore) -> Result<Address> {
+        let address = self.generate_address();
+
+        let wallets = store.get_wallets_bucket()?;
+        let private_key = Keystore::seal(store, &self.private_key.to_bytes())?;
+        wallets.set(address.to_bytes(), private_key)?;
 
         Ok(address)
     }
 
-    pub fn get_all_addresses(store: &AppStore) -> Result<Vec<HashHex>> {
+    pub fn get_all_addresses(store: &AppStore) -> Result<Vec<Address>> {
         let wallets = store.get_wallets_bucket()?;
 
-        let addresses: Vec<HashHex> = wallets
-            .iter()
-            .map(|k| {
-                k.expect("Wallets item error")
-                    .key()
-                    .expect("Wallets key error")
-            })
-            .collect();
+        let mut addresses = Vec::new();
+        for item in wallets.iter() {
+            let address: Vec<u8> = item?.key()?;
+            addresses.push(Address::from_bytes(&address)?);
+        }
+
         Ok(addresses)
     }
 
-    pub fn generate_address(&self) -> HashHex {
-        let pub_key_bytes = self.pub_key_bytes_vec();
+    /// Rewrites stored wallet and watch-only addresses of the legacy format to the
+    /// current network, returns how many were moved. Fails without changes when an
+    /// address of another network is stored, as the store belongs to that network.
+    pub fn migrate_addresses(store: &AppStore) -> Result<usize> {
+        let wallets = store.get_wallets_bucket()?;
+        let watch_only = store.get_watch_only_bucket()?;
 
-        let pub_key_hash = Self::hash_pub_key(pub_key_bytes);
+        // Both buckets are checked before either is written
+        let mut wallets_batch = Batch::new();
+        let mut watch_only_batch = Batch::new();
+        let moved = Self::batch_legacy_addresses(&wallets, &mut wallets_batch)?
+            + Self::batch_legacy_addresses(&watch_only, &mut watch_only_batch)?;
 
-        let mut payload: Vec<u8> = VERSION.to_ne_bytes().to_vec();
-        payload.extend(pub_key_hash.to_vec());
+        wallets.batch(wallets_batch)?;
+        watch_only.batch(watch_only_batch)?;
 
-        let checksum = Self::checksum_hash(payload.clone())[..4].to_vec();
+        Ok(moved)
+    }
 
-        payload.extend(checksum);
+    /// Adds re-keying of the legacy addresses of `bucket` into `batch`, returns their count
+    fn batch_legacy_addresses(
+        bucket: &Bucket<Vec<u8>, Vec<u8>>,
+        batch: &mut Batch<Vec<u8>, Vec<u8>>,
+    ) -> Result<usize> {
+        let mut moved = 0;
+
+        for item in bucket.iter() {
+            let item = item?;
+            let key: Vec<u8> = item.key()?;
+
+            match Address::from_bytes(&key) {
+                Ok(_) => continue,
+                Err(e @ AddressError::WrongNetwork(_)) => return Err(e.into()),
+                Err(_) => {}
+            }
 
-        let encoded = bs58::encode(payload);
+            if let Some(address) = Address::migrate(&key) {
+                let value: Vec<u8> = item.value()?;
 
-        HashHex(encoded.into_vec())
+                batch.remove(key)?;
+                batch.set(address.to_bytes(), value)?;
+                moved += 1;
+            }
+        }
+
+        Ok(moved)
     }
 
-    pub fn get_by(address: &str, store: &AppStore) -> Option<Wallet> {
-        let wallets = store.get_wallets_bucket().unwrap();
+    pub fn generate_address(&self) -> Address {
+        Address::new(Self::hash_pub_key(self.pub_key_bytes_vec()))
+    }
 
-        println!("Wallets in store: {:#?}", wallets.len());
+    pub fn contains(address: &Address, store: &AppStore) -> Result<bool> {
+        let wallets = store.get_wallets_bucket()?;
 
-        if wallets.is_empty() {
-            return None;
-        }
+        Ok(wallets.contains(address.to_bytes().as_slice())?)
+    }
 
-        let address = hex::decode(address).unwrap();
-        let address = address.as_slice();
+    /// Local wallet with its private key, which needs the wallet to be unlocked
+    /// once it has a passphrase
+    pub fn get_by(address: &Address, store: &AppStore) -> Result<Wallet> {
+        let wallets = store.get_wallets_bucket()?;
 
-        let private_key = wallets.get(address).ok().flatten();
+        println!("Wallets in store: {:#?}", wallets.len());
 
-        let private_key = match SigningKey::from_bytes(private_key?.as_slice()) {
-            Ok(v) => v,
-            Err(e) => {
-                println!("{}", e);
-                return None;
-            }
-        };
+        let private_key = wallets
+            .get(address.to_bytes().as_slice())?
+            .ok_or(WalletNotFoundError)?;
+        let private_key = Keystore::open(store, &private_key)?;
 
+        let private_key = SigningKey::from_bytes(private_key.as_slice())?;
         let public_key = VerifyingKey::from(&private_key);
 
-        Some(Wallet {
+        Ok(Wallet {
             private_key,
             public_key,
         })
     }
 
-    pub fn pub_key_bytes_vec(&self) -> Vec<u8> {
-        let pub_key = self.public_key.to_encoded_point(false);
+    /// Base58 of the version byte, the private key and its checksum, like addresses
+    pub fn export_private_key(&self) -> String {
+        let mut payload = vec![PRIVATE_KEY_VERSION];
+        payload.extend(self.private_key.to_bytes());
 
-        pub_key.as_bytes().to_vec()
-    }
+        payload.extend(checksum(&payload));
 
-    pub fn hash_pub_key(key: Vec<u8>) -> HashHex {
-        let mut hasher = Sha256::new();
-        hasher.update(key);
-        let result = hasher.finalize();
+        bs58::encode(payload).into_string()
+    }
 
-        let mut hasher = Ripemd160::new();
-        hasher.update(result);
-        let result = hasher.finalize().to_vec();
+    pub fn import_private_key(encoded: &str) -> Result<Wallet> {
+        let bytes = bs58::decode(encoded.trim())
+            .into_vec()
+            .map_err(|_| BadPrivateKeyError)?;
 
-        HashHex(result)
-    }
+        if bytes.len() != 37 || bytes[0] != PRIVATE_KEY_VERSION {
+            return Err(BadPrivateKeyError.into());
+        }
 
-    pub fn retrieve_pub_key_hash(address: &str) -> Result<HashHex> {
-        let address = hex::decode(address)?;
+        let (payload, sum) = bytes.split_at(33);
+        if checksum(payload) != sum {
+            return Err(BadPrivateKeyError.into());
+        }
 
-        let bytes = bs58::decode(address).into_vec()?;
-        let pub_key_hash = bytes.as_slice()[2..bytes.len() - 4].to_vec();
+        let private_key = SigningKey::from_bytes(&payload[1..]).map_err(|_| BadPrivateKeyError)?;
+        let public_key = VerifyingKey::from(&private_key);
 
-        Ok(HashHex(pub_key_hash))
+        Ok(Wallet {
+            private_key,
+            public_key,
+        })
     }
 
-    fn checksum_hash(payload: Vec<u8>) -> Vec<u8> {
-        let mut hasher = Sha256::new();
-        hasher.update(payload);
-        let result = hasher.finalize();
+    pub fn pub_key_bytes_vec(&self) -> Vec<u8> {
+        let pub_key = self.public_key.to_encoded_point(false);
+
+        pub_key.as_bytes().to_vec()
+    }
 
-        let mut hasher = Sha256::new();
-        hasher.update(result);
-        hasher.finalize().to_vec()
+    pub fn hash_pub_key(key: Vec<u8>) -> HashHex {
+        HashHex(hash160(&key))
     }
 }
@@ -0,0 +1,40 @@
+use crate::{store::AppStore, utils::Result};
+
+use super::address::Address;
+
+/// Addresses which are tracked without their private keys, so nothing can be
+/// signed for them
+pub struct WatchOnly;
+
+impl WatchOnly {
+    pub fn add(address: &Address, store: &AppStore) -> Result<()> {
+        let bucket = store.get_watch_only_bucket()?;
+        bucket.set(address.to_bytes(), address.pub_key_hash().to_vec())?;
+
+        Ok(())
+    }
+
+    /// Whether `address` was watched
+    pub fn remove(address: &Address, store: &AppStore) -> Result<bool> {
+        let bucket = store.get_watch_only_bucket()?;
+        if !bucket.contains(address.to_bytes().as_slice())? {
+            return Ok(false);
+        }
+
+        bucket.remove(address.to_bytes())?;
+
+        Ok(true)
+    }
+
+    pub fn get_all(store: &AppStore) -> Result<Vec<Address>> {
+        let bucket = store.get_watch_only_bucket()?;
+
+        let mut addresses = Vec::new();
+        for item in bucket.iter() {
+            let address: Vec<u8> = item?.key()?;
+            addresses.push(Address::from_bytes(&address)?);
+        }
+
+        Ok(addresses)
+    }
+}
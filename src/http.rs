@@ -1,21 +1,136 @@
 use std::sync::Arc;
 
+use crate::blockchain::address::Address;
 use crate::blockchain::block::Block;
-use crate::blockchain::transaction::Transaction;
+use crate::blockchain::chain_params::CHAIN_PARAMS;
+use crate::blockchain::coin_selection::CoinSelection;
+use crate::blockchain::hd_wallet::{HdKeychain, DEFAULT_ACCOUNT_PATH, DEFAULT_GAP_LIMIT};
+use crate::blockchain::history::{AddressHistory, HistoryEntry};
+use crate::blockchain::keystore::{
+    Keystore, NotEncryptedError, WalletLockedError, WrongPassphraseError,
+};
+use crate::blockchain::mempool::Mempool;
+use crate::blockchain::miner::MinerStatus;
+use crate::blockchain::proof_of_work::PowError;
+use crate::blockchain::reorg::{ChainTip, ChainUpdate};
+use crate::blockchain::script::Htlc;
+use crate::blockchain::transaction::{Transaction, TxLocks};
+use crate::blockchain::tx_builder::TxBuilder;
+use crate::blockchain::tx_index::TxIndex;
 use crate::blockchain::utxo_set::UTXOSet;
-use crate::blockchain::wallet::Wallet;
+use crate::blockchain::wallet::{Wallet, WalletNotFoundError};
+use crate::blockchain::watch_only::WatchOnly;
 use crate::blockchain::Blockchain;
+use crate::network::Peer;
 use crate::utils::HashHex;
 use crate::AppState;
-use actix_web::web::{Data, Json, Path};
-use actix_web::{error, get, post, Responder, Result};
+use actix_web::error::BlockingError;
+use actix_web::web::{self, Data, Json, Path, Query};
+use actix_web::{delete, error, get, post, Responder, Result};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 #[derive(Deserialize)]
 pub struct SendBody {
-    from: String,
-    to: String,
+    from: Address,
+    to: Option<Address>,
+    amount: Option<i32>,
+    /// Several recipients paid by one transaction, along with `to` if it is set
+    #[serde(default)]
+    outputs: Vec<Payment>,
+    #[serde(default)]
+    fee: u32,
+    #[serde(flatten)]
+    locks: TxLocks,
+    #[serde(default)]
+    coin_selection: CoinSelection,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Payment {
+    to: Address,
+    amount: i32,
+}
+
+#[derive(Deserialize)]
+pub struct MultisigBody {
+    from: Address,
+    required: usize,
+    pub_keys: Vec<HashHex>,
+    amount: i32,
+    #[serde(default)]
+    fee: u32,
+    #[serde(flatten)]
+    locks: TxLocks,
+}
+
+#[derive(Deserialize)]
+pub struct MultisigSpendBody {
+    tx_id: HashHex,
+    output_index: i32,
+    to: Address,
+    amount: i32,
+    #[serde(default)]
+    fee: u32,
+    #[serde(flatten)]
+    locks: TxLocks,
+}
+
+#[derive(Deserialize)]
+pub struct DataBody {
+    from: Address,
+    data: HashHex,
+    #[serde(default)]
+    fee: u32,
+}
+
+#[derive(Deserialize)]
+pub struct HtlcBody {
+    from: Address,
+    to: Address,
+    /// Wallet the coins go back to after the timeout, the sender one by default
+    refund: Option<Address>,
+    hash: HashHex,
+    timeout: u64,
     amount: i32,
+    #[serde(default)]
+    fee: u32,
+}
+
+#[derive(Deserialize)]
+pub struct HtlcClaimBody {
+    tx_id: HashHex,
+    output_index: i32,
+    address: Address,
+    preimage: HashHex,
+    #[serde(default)]
+    fee: u32,
+}
+
+#[derive(Deserialize)]
+pub struct HtlcRefundBody {
+    tx_id: HashHex,
+    output_index: i32,
+    address: Address,
+    #[serde(default)]
+    fee: u32,
+}
+
+#[derive(Serialize)]
+pub struct HtlcSecretResponse {
+    preimage: HashHex,
+    hash: HashHex,
+}
+
+#[derive(Deserialize)]
+pub struct CosignBody {
+    address: Address,
+    transaction: Transaction,
+}
+
+#[derive(Deserialize)]
+pub struct MineBody {
+    address: Address,
 }
 
 #[derive(Serialize)]
@@ -23,14 +138,189 @@ pub struct GetBalanceReponse {
     balance: u32,
 }
 
+#[derive(Serialize)]
+pub struct GetSupplyResponse {
+    height: u64,
+    issued: u64,
+    unspent: u64,
+    max_supply: Option<u64>,
+    next_block_subsidy: u32,
+}
+
+#[derive(Serialize)]
+pub struct TxIndexResponse {
+    enabled: bool,
+}
+
 #[derive(Deserialize)]
-pub struct CreateBlockchainBody {
+pub struct BlocksRangeQuery {
+    from: u64,
+    to: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct ChainUpdateResponse {
+    tip: HashHex,
+    height: u64,
+    disconnected: Vec<HashHex>,
+    connected: Vec<HashHex>,
+}
+
+impl ChainUpdateResponse {
+    fn new(blockchain: &Blockchain, update: ChainUpdate) -> Result<Self> {
+        let height = blockchain
+            .height()
+            .map_err(error::ErrorInternalServerError)?;
+
+        Ok(ChainUpdateResponse {
+            tip: blockchain.tip.clone(),
+            height,
+            disconnected: update.disconnected.into_iter().map(|b| b.hash).collect(),
+            connected: update.connected.into_iter().map(|b| b.hash).collect(),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ConnectPeerBody {
     address: String,
 }
 
+#[derive(Deserialize)]
+pub struct RewindBody {
+    height: u64,
+}
+
+#[derive(Deserialize)]
+pub struct CreateBlockchainBody {
+    address: Address,
+}
+
 #[derive(Serialize)]
 pub struct CreateWalletResponse {
-    wallet_address: HashHex,
+    wallet_address: Address,
+}
+
+#[derive(Deserialize)]
+pub struct HdWalletBody {
+    /// Restores wallets of this backup phrase, a new one is generated when it is not set
+    mnemonic: Option<String>,
+    account_path: Option<String>,
+    gap_limit: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct HdWalletResponse {
+    /// Shown only once, when the keychain is created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mnemonic: Option<String>,
+    account_path: String,
+    next_index: u32,
+    addresses: Vec<Address>,
+}
+
+#[derive(Deserialize)]
+pub struct UnlockBody {
+    passphrase: String,
+}
+
+#[derive(Deserialize)]
+pub struct PassphraseBody {
+    /// Needed once the wallet has a passphrase
+    old_passphrase: Option<String>,
+    passphrase: String,
+}
+
+#[derive(Serialize)]
+pub struct KeystoreResponse {
+    encrypted: bool,
+    locked: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ImportWalletBody {
+    private_key: String,
+}
+
+#[derive(Serialize)]
+pub struct ExportWalletResponse {
+    address: Address,
+    private_key: String,
+}
+
+#[derive(Deserialize)]
+pub struct WatchOnlyBody {
+    address: Address,
+}
+
+#[derive(Serialize)]
+pub struct WatchOnlyResponse {
+    address: Address,
+    balance: u32,
+}
+
+#[derive(Serialize)]
+pub struct WalletResponse {
+    address: Address,
+    pub_key: HashHex,
+}
+
+/// Address of a path segment, a malformed one is a bad request rather than a missing route
+fn parse_address(address: &str) -> Result<Address> {
+    address.parse().map_err(error::ErrorBadRequest)
+}
+
+/// Status code of a wallet access error
+fn wallet_error(e: Box<dyn std::error::Error>) -> actix_web::Error {
+    if e.is::<WalletNotFoundError>() {
+        error::ErrorNotFound(e)
+    } else if e.is::<WalletLockedError>() || e.is::<WrongPassphraseError>() {
+        error::ErrorForbidden(e)
+    } else if e.is::<NotEncryptedError>() {
+        error::ErrorConflict(e)
+    } else {
+        error::ErrorInternalServerError(e)
+    }
+}
+
+/// Balance of the main chain, zero while there is no blockchain
+fn balance_of(store: &crate::store::AppStore, pub_key_hash: &HashHex) -> Result<u32> {
+    if !Blockchain::exists(store) {
+        return Ok(0);
+    }
+
+    let blockchain = Blockchain::new(None, store).map_err(error::ErrorInternalServerError)?;
+
+    let utxo_set = UTXOSet {
+        blockchain: &blockchain,
+    };
+
+    let utxo = utxo_set
+        .find_utxo(pub_key_hash)
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(utxo.iter().fold(0, |acc, out| acc + out.value))
+}
+
+fn watch_only_response(store: &crate::store::AppStore) -> Result<Json<Vec<WatchOnlyResponse>>> {
+    let watched = WatchOnly::get_all(store).map_err(error::ErrorInternalServerError)?;
+
+    let mut addresses = vec![];
+    for address in watched {
+        addresses.push(WatchOnlyResponse {
+            balance: balance_of(store, address.pub_key_hash())?,
+            address,
+        });
+    }
+
+    Ok(Json(addresses))
+}
+
+fn keystore_response(store: &crate::store::AppStore) -> Result<Json<KeystoreResponse>> {
+    Ok(Json(KeystoreResponse {
+        encrypted: Keystore::is_encrypted(store).map_err(error::ErrorInternalServerError)?,
+        locked: Keystore::is_locked(store).map_err(error::ErrorInternalServerError)?,
+    }))
 }
 
 #[get("/")]
@@ -52,6 +342,124 @@ pub async fn get_blockchain(state: Data<AppState>) -> Result<Json<Vec<Block>>> {
     Ok(Json(buffer))
 }
 
+#[get("/blocks/{height}")]
+pub async fn get_block_by_height(state: Data<AppState>, path: Path<(u64,)>) -> Result<Json<Block>> {
+    let store = Arc::clone(&state.store);
+    let store = store.lock().unwrap();
+
+    if !Blockchain::exists(&store) {
+        return Err(error::ErrorNotFound("Blockchain not initialized yet"));
+    }
+
+    let blockchain = Blockchain::new(None, &store).map_err(error::ErrorInternalServerError)?;
+
+    let block = blockchain
+        .get_block_by_height(path.into_inner().0)
+        .map_err(error::ErrorInternalServerError)?
+        .ok_or_else(|| error::ErrorNotFound("Block with this height is not found"))?;
+
+    Ok(Json(block))
+}
+
+#[get("/blocks")]
+pub async fn get_blocks_range(
+    state: Data<AppState>,
+    query: Query<BlocksRangeQuery>,
+) -> Result<Json<Vec<Block>>> {
+    let store = Arc::clone(&state.store);
+    let store = store.lock().unwrap();
+
+    if !Blockchain::exists(&store) {
+        return Err(error::ErrorNotFound("Blockchain not initialized yet"));
+    }
+
+    let blockchain = Blockchain::new(None, &store).map_err(error::ErrorInternalServerError)?;
+
+    let to = match query.to {
+        Some(v) => v,
+        None => blockchain
+            .height()
+            .map_err(error::ErrorInternalServerError)?,
+    };
+
+    let blocks = blockchain
+        .get_blocks_range(query.from, to)
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(Json(blocks))
+}
+
+#[post("/blocks")]
+pub async fn submit_block(
+    state: Data<AppState>,
+    body: Json<Block>,
+) -> Result<Json<ChainUpdateResponse>> {
+    let store = Arc::clone(&state.store);
+    let store = store.lock().unwrap();
+
+    if !Blockchain::exists(&store) {
+        return Err(error::ErrorNotFound("Blockchain not initialized yet"));
+    }
+
+    let mut blockchain = Blockchain::new(None, &store).map_err(error::ErrorInternalServerError)?;
+
+    let update = state
+        .node
+        .accept_block(&mut blockchain, body.into_inner(), None)
+        .map_err(error::ErrorBadRequest)?;
+
+    Ok(Json(ChainUpdateResponse::new(&blockchain, update)?))
+}
+
+#[post("/chain/rewind")]
+pub async fn rewind_chain(
+    state: Data<AppState>,
+    body: Json<RewindBody>,
+) -> Result<Json<ChainUpdateResponse>> {
+    let store = Arc::clone(&state.store);
+    let store = store.lock().unwrap();
+
+    if !Blockchain::exists(&store) {
+        return Err(error::ErrorNotFound("Blockchain not initialized yet"));
+    }
+
+    let mut blockchain = Blockchain::new(None, &store).map_err(error::ErrorInternalServerError)?;
+
+    let update = blockchain
+        .rewind(body.height)
+        .map_err(error::ErrorInternalServerError)?;
+
+    let mempool = Mempool {
+        blockchain: &blockchain,
+    };
+    mempool
+        .apply_chain_update(&update)
+        .map_err(error::ErrorInternalServerError)?;
+
+    // Running jobs may be built on a disconnected tip
+    state.miner.restart();
+
+    Ok(Json(ChainUpdateResponse::new(&blockchain, update)?))
+}
+
+#[get("/chain/tips")]
+pub async fn get_chain_tips(state: Data<AppState>) -> Result<Json<Vec<ChainTip>>> {
+    let store = Arc::clone(&state.store);
+    let store = store.lock().unwrap();
+
+    if !Blockchain::exists(&store) {
+        return Err(error::ErrorNotFound("Blockchain not initialized yet"));
+    }
+
+    let blockchain = Blockchain::new(None, &store).map_err(error::ErrorInternalServerError)?;
+
+    let tips = blockchain
+        .chain_tips()
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(Json(tips))
+}
+
 #[post("/")]
 pub async fn create_blockchain(
     state: Data<AppState>,
@@ -90,20 +498,16 @@ pub async fn get_balance(state: Data<AppState>, path: Path<(String,)>) -> Result
         return Err(error::ErrorNotFound("Blockchain not initialized yet"));
     }
 
-    let address = path.into_inner().0;
+    let address = parse_address(&path.into_inner().0)?;
 
-    let blockchain =
-        Blockchain::new(Some(address.clone()), &store).map_err(error::ErrorInternalServerError)?;
-
-    let pub_key_hash =
-        Wallet::retrieve_pub_key_hash(&address).map_err(error::ErrorInternalServerError)?;
+    let blockchain = Blockchain::new(None, &store).map_err(error::ErrorInternalServerError)?;
 
     let utxo_set = UTXOSet {
         blockchain: &blockchain,
     };
 
     let utxo = utxo_set
-        .find_utxo(&pub_key_hash)
+        .find_utxo(address.pub_key_hash())
         .map_err(error::ErrorInternalServerError)?;
 
     let balance = utxo.iter().fold(0, |acc, out| acc + out.value);
@@ -111,70 +515,841 @@ pub async fn get_balance(state: Data<AppState>, path: Path<(String,)>) -> Result
     Ok(Json(GetBalanceReponse { balance }))
 }
 
-#[post("/coins")]
-pub async fn add_chain_block(state: Data<AppState>, body: Json<SendBody>) -> Result<Json<Block>> {
+#[get("/coins/{address}/history")]
+pub async fn get_history(
+    state: Data<AppState>,
+    path: Path<(String,)>,
+) -> Result<Json<Vec<HistoryEntry>>> {
     let store = Arc::clone(&state.store);
     let store = store.lock().unwrap();
 
-    let from = body.from.clone();
+    if !Blockchain::exists(&store) {
+        return Err(error::ErrorNotFound("Blockchain not initialized yet"));
+    }
 
-    let mut blockchain = Blockchain::new(Some(from.to_owned()), &store).unwrap(); //.map_err(error::ErrorInternalServerError)?;
+    let address = parse_address(&path.into_inner().0)?;
 
-    if blockchain.tip.0.is_empty() {
-        return Err(error::ErrorInternalServerError("Blockchain tip is empty"));
-    }
+    let blockchain = Blockchain::new(None, &store).map_err(error::ErrorInternalServerError)?;
 
-    if body.amount <= 0 {
-        return Err(error::ErrorBadRequest(
-            "Amount value can't be low or equal than zero",
-        ));
-    }
+    let history = AddressHistory {
+        blockchain: &blockchain,
+    };
 
-    if body.from == body.to {
-        return Err(error::ErrorBadRequest("You can't send money to yourself"));
-    }
+    let entries = history
+        .get(address.pub_key_hash())
+        .map_err(error::ErrorInternalServerError)?;
 
-    let transaction = Transaction::new_utxo(
-        from.to_owned(),
-        body.to.to_owned(),
-        body.amount as u32,
-        &blockchain,
-    )
-    .map_err(error::ErrorInternalServerError)?;
+    Ok(Json(entries))
+}
+
+#[get("/supply")]
+pub async fn get_supply(state: Data<AppState>) -> Result<Json<GetSupplyResponse>> {
+    let store = Arc::clone(&state.store);
+    let store = store.lock().unwrap();
+
+    if !Blockchain::exists(&store) {
+        return Err(error::ErrorNotFound("Blockchain not initialized yet"));
+    }
 
-    // Block miner reward
-    let coinbase_tx =
-        Transaction::new_coinbase(from, None, &store).map_err(error::ErrorInternalServerError)?;
+    let blockchain = Blockchain::new(None, &store).map_err(error::ErrorInternalServerError)?;
 
-    let added_block = blockchain
-        .add_block(vec![coinbase_tx, transaction])
+    let height = blockchain
+        .height()
         .map_err(error::ErrorInternalServerError)?;
 
     let utxo_set = UTXOSet {
         blockchain: &blockchain,
     };
-    utxo_set
-        .update(&added_block)
+    let unspent = utxo_set
+        .total_value()
         .map_err(error::ErrorInternalServerError)?;
 
-    Ok(Json(added_block))
+    Ok(Json(GetSupplyResponse {
+        height,
+        issued: CHAIN_PARAMS.issued_supply(height + 1),
+        unspent,
+        max_supply: CHAIN_PARAMS.max_supply,
+        next_block_subsidy: CHAIN_PARAMS.block_subsidy(height + 1),
+    }))
 }
 
-#[post("/wallet")]
-pub async fn new_wallet(state: Data<AppState>) -> Result<Json<CreateWalletResponse>> {
+#[get("/transactions/{id}")]
+pub async fn get_transaction(
+    state: Data<AppState>,
+    path: Path<(String,)>,
+) -> Result<Json<Transaction>> {
     let store = Arc::clone(&state.store);
-    let wallet_address = Wallet::create(store).map_err(error::ErrorInternalServerError)?;
+    let store = store.lock().unwrap();
 
-    Ok(Json(CreateWalletResponse { wallet_address }))
+    if !Blockchain::exists(&store) {
+        return Err(error::ErrorNotFound("Blockchain not initialized yet"));
+    }
+
+    let blockchain = Blockchain::new(None, &store).map_err(error::ErrorInternalServerError)?;
+
+    let tx_id = hex::decode(path.into_inner().0).map_err(error::ErrorBadRequest)?;
+
+    let transaction = blockchain
+        .find_transaction(&tx_id.into())
+        .ok_or_else(|| error::ErrorNotFound("Transaction is not found"))?;
+
+    Ok(Json(transaction))
 }
 
-#[get("/wallet")]
-pub async fn get_wallets(state: Data<AppState>) -> Result<Json<Vec<HashHex>>> {
+#[post("/txindex")]
+pub async fn rebuild_tx_index(state: Data<AppState>) -> Result<Json<TxIndexResponse>> {
     let store = Arc::clone(&state.store);
     let store = store.lock().unwrap();
 
-    let wallet_address =
-        Wallet::get_all_addresses(&store).map_err(error::ErrorInternalServerError)?;
+    if !Blockchain::exists(&store) {
+        return Err(error::ErrorNotFound("Blockchain not initialized yet"));
+    }
 
-    Ok(Json(wallet_address))
+    let blockchain = Blockchain::new(None, &store).map_err(error::ErrorInternalServerError)?;
+
+    let tx_index = TxIndex {
+        blockchain: &blockchain,
+    };
+    tx_index
+        .reindex()
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(Json(TxIndexResponse {
+        enabled: tx_index.is_enabled(),
+    }))
+}
+
+#[delete("/txindex")]
+pub async fn drop_tx_index(state: Data<AppState>) -> Result<Json<TxIndexResponse>> {
+    let store = Arc::clone(&state.store);
+    let store = store.lock().unwrap();
+
+    store
+        .drop_txindex()
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(Json(TxIndexResponse {
+        enabled: store.has_txindex(),
+    }))
+}
+
+#[post("/coins")]
+pub async fn send_coins(state: Data<AppState>, body: Json<SendBody>) -> Result<Json<Transaction>> {
+    let store = Arc::clone(&state.store);
+    let store = store.lock().unwrap();
+
+    if !Blockchain::exists(&store) {
+        return Err(error::ErrorNotFound("Blockchain not initialized yet"));
+    }
+
+    let blockchain = Blockchain::new(None, &store).map_err(error::ErrorInternalServerError)?;
+
+    let mut payments = body.outputs.clone();
+    match (&body.to, body.amount) {
+        (Some(to), Some(amount)) => payments.insert(
+            0,
+            Payment {
+                to: to.to_owned(),
+                amount,
+            },
+        ),
+        (None, None) => {}
+        _ => {
+            return Err(error::ErrorBadRequest(
+                "Recipient and amount have to be set together",
+            ))
+        }
+    }
+
+    if payments.is_empty() {
+        return Err(error::ErrorBadRequest("Transaction has no recipients"));
+    }
+
+    let mut builder = TxBuilder::new(&body.from, &blockchain)
+        .fee(body.fee)
+        .locks(body.locks)
+        .coin_selection(body.coin_selection);
+
+    for payment in payments {
+        if payment.amount <= 0 {
+            return Err(error::ErrorBadRequest(
+                "Amount value can't be low or equal than zero",
+            ));
+        }
+
+        if payment.to == body.from {
+            return Err(error::ErrorBadRequest("You can't send money to yourself"));
+        }
+
+        builder = builder.pay_to(&payment.to, payment.amount as u32);
+    }
+
+    let transaction = builder.build().map_err(wallet_error)?;
+
+    state
+        .node
+        .accept_transaction(&blockchain, transaction.clone(), None)
+        .map_err(error::ErrorBadRequest)?;
+
+    Ok(Json(transaction))
+}
+
+#[post("/multisig")]
+pub async fn send_multisig(
+    state: Data<AppState>,
+    body: Json<MultisigBody>,
+) -> Result<Json<Transaction>> {
+    let store = Arc::clone(&state.store);
+    let store = store.lock().unwrap();
+
+    if !Blockchain::exists(&store) {
+        return Err(error::ErrorNotFound("Blockchain not initialized yet"));
+    }
+
+    let blockchain = Blockchain::new(None, &store).map_err(error::ErrorInternalServerError)?;
+
+    if body.amount <= 0 {
+        return Err(error::ErrorBadRequest(
+            "Amount value can't be low or equal than zero",
+        ));
+    }
+
+    let pub_keys: Vec<Vec<u8>> = body.pub_keys.iter().map(|key| key.to_vec()).collect();
+
+    let transaction = Transaction::new_multisig(
+        body.from.to_owned(),
+        body.required,
+        &pub_keys,
+        body.amount as u32,
+        body.fee,
+        body.locks,
+        &blockchain,
+    )
+    .map_err(error::ErrorBadRequest)?;
+
+    state
+        .node
+        .accept_transaction(&blockchain, transaction.clone(), None)
+        .map_err(error::ErrorBadRequest)?;
+
+    Ok(Json(transaction))
+}
+
+#[post("/multisig/spend")]
+pub async fn spend_multisig(
+    state: Data<AppState>,
+    body: Json<MultisigSpendBody>,
+) -> Result<Json<Transaction>> {
+    let store = Arc::clone(&state.store);
+    let store = store.lock().unwrap();
+
+    if !Blockchain::exists(&store) {
+        return Err(error::ErrorNotFound("Blockchain not initialized yet"));
+    }
+
+    let blockchain = Blockchain::new(None, &store).map_err(error::ErrorInternalServerError)?;
+
+    if body.amount <= 0 {
+        return Err(error::ErrorBadRequest(
+            "Amount value can't be low or equal than zero",
+        ));
+    }
+
+    let transaction = Transaction::new_multisig_spend(
+        body.tx_id.clone(),
+        body.output_index,
+        body.to.to_owned(),
+        body.amount as u32,
+        body.fee,
+        body.locks,
+        &blockchain,
+    )
+    .map_err(error::ErrorBadRequest)?;
+
+    Ok(Json(transaction))
+}
+
+#[post("/multisig/sign")]
+pub async fn cosign_multisig(
+    state: Data<AppState>,
+    body: Json<CosignBody>,
+) -> Result<Json<Transaction>> {
+    let store = Arc::clone(&state.store);
+    let store = store.lock().unwrap();
+
+    if !Blockchain::exists(&store) {
+        return Err(error::ErrorNotFound("Blockchain not initialized yet"));
+    }
+
+    let blockchain = Blockchain::new(None, &store).map_err(error::ErrorInternalServerError)?;
+
+    let wallet = Wallet::get_by(&body.address, &store).map_err(wallet_error)?;
+
+    let mut transaction = body.into_inner().transaction;
+
+    blockchain
+        .cosign_transaction(&mut transaction, &wallet.private_key)
+        .map_err(error::ErrorBadRequest)?;
+
+    Ok(Json(transaction))
+}
+
+#[post("/data")]
+pub async fn publish_data(
+    state: Data<AppState>,
+    body: Json<DataBody>,
+) -> Result<Json<Transaction>> {
+    let store = Arc::clone(&state.store);
+    let store = store.lock().unwrap();
+
+    if !Blockchain::exists(&store) {
+        return Err(error::ErrorNotFound("Blockchain not initialized yet"));
+    }
+
+    let blockchain = Blockchain::new(None, &store).map_err(error::ErrorInternalServerError)?;
+
+    if body.data.0.is_empty() {
+        return Err(error::ErrorBadRequest("Data can't be empty"));
+    }
+
+    let transaction = Transaction::new_data(
+        body.from.to_owned(),
+        body.data.to_vec(),
+        body.fee,
+        &blockchain,
+    )
+    .map_err(error::ErrorBadRequest)?;
+
+    state
+        .node
+        .accept_transaction(&blockchain, transaction.clone(), None)
+        .map_err(error::ErrorBadRequest)?;
+
+    Ok(Json(transaction))
+}
+
+#[post("/htlc/secret")]
+pub async fn new_htlc_secret() -> Result<Json<HtlcSecretResponse>> {
+    let preimage: [u8; 32] = rand::random();
+    let hash = Sha256::digest(preimage).to_vec();
+
+    Ok(Json(HtlcSecretResponse {
+        preimage: preimage.to_vec().into(),
+        hash: hash.into(),
+    }))
+}
+
+#[post("/htlc")]
+pub async fn send_htlc(state: Data<AppState>, body: Json<HtlcBody>) -> Result<Json<Transaction>> {
+    let store = Arc::clone(&state.store);
+    let store = store.lock().unwrap();
+
+    if !Blockchain::exists(&store) {
+        return Err(error::ErrorNotFound("Blockchain not initialized yet"));
+    }
+
+    let blockchain = Blockchain::new(None, &store).map_err(error::ErrorInternalServerError)?;
+
+    if body.amount <= 0 {
+        return Err(error::ErrorBadRequest(
+            "Amount value can't be low or equal than zero",
+        ));
+    }
+
+    let refund = body.refund.as_ref().unwrap_or(&body.from);
+
+    let htlc = Htlc {
+        hash: body.hash.to_vec(),
+        recipient: body.to.pub_key_hash().clone(),
+        timeout: body.timeout,
+        refund: refund.pub_key_hash().clone(),
+    };
+
+    let transaction = Transaction::new_htlc(
+        body.from.to_owned(),
+        &htlc,
+        body.amount as u32,
+        body.fee,
+        &blockchain,
+    )
+    .map_err(error::ErrorBadRequest)?;
+
+    state
+        .node
+        .accept_transaction(&blockchain, transaction.clone(), None)
+        .map_err(error::ErrorBadRequest)?;
+
+    Ok(Json(transaction))
+}
+
+#[post("/htlc/claim")]
+pub async fn claim_htlc(
+    state: Data<AppState>,
+    body: Json<HtlcClaimBody>,
+) -> Result<Json<Transaction>> {
+    let store = Arc::clone(&state.store);
+    let store = store.lock().unwrap();
+
+    if !Blockchain::exists(&store) {
+        return Err(error::ErrorNotFound("Blockchain not initialized yet"));
+    }
+
+    let blockchain = Blockchain::new(None, &store).map_err(error::ErrorInternalServerError)?;
+
+    let transaction = Transaction::new_htlc_claim(
+        body.tx_id.clone(),
+        body.output_index,
+        body.address.to_owned(),
+        body.preimage.to_vec(),
+        body.fee,
+        &blockchain,
+    )
+    .map_err(error::ErrorBadRequest)?;
+
+    state
+        .node
+        .accept_transaction(&blockchain, transaction.clone(), None)
+        .map_err(error::ErrorBadRequest)?;
+
+    Ok(Json(transaction))
+}
+
+#[post("/htlc/refund")]
+pub async fn refund_htlc(
+    state: Data<AppState>,
+    body: Json<HtlcRefundBody>,
+) -> Result<Json<Transaction>> {
+    let store = Arc::clone(&state.store);
+    let store = store.lock().unwrap();
+
+    if !Blockchain::exists(&store) {
+        return Err(error::ErrorNotFound("Blockchain not initialized yet"));
+    }
+
+    let blockchain = Blockchain::new(None, &store).map_err(error::ErrorInternalServerError)?;
+
+    let transaction = Transaction::new_htlc_refund(
+        body.tx_id.clone(),
+        body.output_index,
+        body.address.to_owned(),
+        body.fee,
+        &blockchain,
+    )
+    .map_err(error::ErrorBadRequest)?;
+
+    state
+        .node
+        .accept_transaction(&blockchain, transaction.clone(), None)
+        .map_err(error::ErrorBadRequest)?;
+
+    Ok(Json(transaction))
+}
+
+#[post("/transactions")]
+pub async fn submit_transaction(
+    state: Data<AppState>,
+    body: Json<Transaction>,
+) -> Result<Json<Transaction>> {
+    let store = Arc::clone(&state.store);
+    let store = store.lock().unwrap();
+
+    if !Blockchain::exists(&store) {
+        return Err(error::ErrorNotFound("Blockchain not initialized yet"));
+    }
+
+    let blockchain = Blockchain::new(None, &store).map_err(error::ErrorInternalServerError)?;
+
+    let transaction = body.into_inner();
+
+    state
+        .node
+        .accept_transaction(&blockchain, transaction.clone(), None)
+        .map_err(error::ErrorBadRequest)?;
+
+    Ok(Json(transaction))
+}
+
+#[get("/mempool")]
+pub async fn get_mempool(state: Data<AppState>) -> Result<Json<Vec<Transaction>>> {
+    let store = Arc::clone(&state.store);
+    let store = store.lock().unwrap();
+
+    if !Blockchain::exists(&store) {
+        return Err(error::ErrorNotFound("Blockchain not initialized yet"));
+    }
+
+    let blockchain = Blockchain::new(None, &store).map_err(error::ErrorInternalServerError)?;
+
+    let mempool = Mempool {
+        blockchain: &blockchain,
+    };
+    let transactions = mempool
+        .transactions()
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(Json(transactions))
+}
+
+#[post("/mine")]
+pub async fn mine_block(state: Data<AppState>, body: Json<MineBody>) -> Result<Json<Block>> {
+    loop {
+        let epoch = state.miner.epoch();
+
+        // Block template is collected under the store lock, but proof-of-work runs without it
+        let template = {
+            let store = state.store.lock().unwrap();
+
+            if !Blockchain::exists(&store) {
+                return Err(error::ErrorNotFound("Blockchain not initialized yet"));
+            }
+
+            let blockchain =
+                Blockchain::new(None, &store).map_err(error::ErrorInternalServerError)?;
+
+            let mempool = Mempool {
+                blockchain: &blockchain,
+            };
+            let (mut transactions, fees) = mempool
+                .select_transactions()
+                .map_err(error::ErrorInternalServerError)?;
+
+            // Block miner reward
+            let height = blockchain
+                .height()
+                .map_err(error::ErrorInternalServerError)?
+                + 1;
+            let fees = u32::try_from(fees).map_err(error::ErrorInternalServerError)?;
+            let coinbase_tx =
+                Transaction::new_coinbase(body.address.to_owned(), None, height, fees, &store)
+                    .map_err(error::ErrorInternalServerError)?;
+            transactions.insert(0, coinbase_tx);
+
+            let bits = blockchain
+                .next_bits()
+                .map_err(error::ErrorInternalServerError)?;
+
+            Block::new_template(blockchain.tip.clone(), height, transactions, bits)
+        };
+
+        let miner = Arc::clone(&state.miner);
+        let mined_block = match web::block(move || miner.mine(template, epoch)).await {
+            Ok(v) => v,
+            Err(BlockingError::Error(PowError::Stale)) => {
+                println!("[!] Block template is outdated, mining again");
+                continue;
+            }
+            Err(BlockingError::Error(PowError::Cancelled)) => {
+                return Err(error::ErrorConflict("Mining is cancelled"));
+            }
+            Err(e) => return Err(error::ErrorInternalServerError(e)),
+        };
+
+        let store = state.store.lock().unwrap();
+
+        let mut blockchain =
+            Blockchain::new(None, &store).map_err(error::ErrorInternalServerError)?;
+
+        if blockchain.tip != mined_block.header.prev_hash {
+            println!("[!] Chain tip changed during mining, mining again");
+            continue;
+        }
+
+        let update = state
+            .node
+            .accept_block(&mut blockchain, mined_block, None)
+            .map_err(error::ErrorInternalServerError)?;
+
+        let added_block = update
+            .connected
+            .last()
+            .cloned()
+            .ok_or_else(|| error::ErrorInternalServerError("Mined block is not connected"))?;
+
+        return Ok(Json(added_block));
+    }
+}
+
+#[get("/mine")]
+pub async fn get_miner_status(state: Data<AppState>) -> Result<Json<MinerStatus>> {
+    Ok(Json(state.miner.status()))
+}
+
+#[delete("/mine")]
+pub async fn cancel_mining(state: Data<AppState>) -> Result<Json<MinerStatus>> {
+    state.miner.cancel();
+
+    Ok(Json(state.miner.status()))
+}
+
+#[get("/peers")]
+pub async fn get_peers(state: Data<AppState>) -> Result<Json<Vec<Peer>>> {
+    Ok(Json(state.node.peers()))
+}
+
+#[post("/peers")]
+pub async fn connect_peer(
+    state: Data<AppState>,
+    body: Json<ConnectPeerBody>,
+) -> Result<Json<Vec<Peer>>> {
+    state
+        .node
+        .connect(&body.address)
+        .map_err(error::ErrorBadRequest)?;
+
+    Ok(Json(state.node.peers()))
+}
+
+#[post("/wallet")]
+pub async fn new_wallet(state: Data<AppState>) -> Result<Json<CreateWalletResponse>> {
+    let store = Arc::clone(&state.store);
+    let wallet_address = Wallet::create(store).map_err(wallet_error)?;
+
+    Ok(Json(CreateWalletResponse { wallet_address }))
+}
+
+#[get("/wallet")]
+pub async fn get_wallets(state: Data<AppState>) -> Result<Json<Vec<Address>>> {
+    let store = Arc::clone(&state.store);
+    let store = store.lock().unwrap();
+
+    let wallet_address =
+        Wallet::get_all_addresses(&store).map_err(error::ErrorInternalServerError)?;
+
+    Ok(Json(wallet_address))
+}
+
+#[post("/wallet/hd")]
+pub async fn create_hd_wallet(
+    state: Data<AppState>,
+    body: Json<HdWalletBody>,
+) -> Result<Json<HdWalletResponse>> {
+    let store = Arc::clone(&state.store);
+    let store = store.lock().unwrap();
+
+    if HdKeychain::exists(&store).map_err(error::ErrorInternalServerError)? {
+        return Err(error::ErrorConflict("HD wallet already exists"));
+    }
+
+    if Keystore::is_locked(&store).map_err(error::ErrorInternalServerError)? {
+        return Err(wallet_error(WalletLockedError.into()));
+    }
+
+    let account_path = body.account_path.as_deref().unwrap_or(DEFAULT_ACCOUNT_PATH);
+
+    let mut keychain = match &body.mnemonic {
+        Some(mnemonic) => HdKeychain::from_mnemonic(mnemonic, account_path),
+        None => HdKeychain::generate(account_path),
+    }
+    .map_err(error::ErrorBadRequest)?;
+
+    let mut addresses = vec![];
+
+    // Wallets of a restored backup up to the last paid one come back right away
+    if body.mnemonic.is_some() && Blockchain::exists(&store) {
+        let blockchain = Blockchain::new(None, &store).map_err(error::ErrorInternalServerError)?;
+        let used = HdKeychain::used_pub_key_hashes(&blockchain);
+
+        let wallets = keychain
+            .scan(&used, body.gap_limit.unwrap_or(DEFAULT_GAP_LIMIT))
+            .map_err(error::ErrorInternalServerError)?;
+
+        for wallet in wallets {
+            addresses.push(
+                wallet
+                    .save(&store)
+                    .map_err(error::ErrorInternalServerError)?,
+            );
+        }
+    }
+
+    keychain
+        .save(&store)
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(Json(HdWalletResponse {
+        mnemonic: Some(keychain.mnemonic),
+        account_path: keychain.account_path,
+        next_index: keychain.next_index,
+        addresses,
+    }))
+}
+
+#[get("/wallet/hd")]
+pub async fn get_hd_wallet(state: Data<AppState>) -> Result<Json<HdWalletResponse>> {
+    let store = Arc::clone(&state.store);
+    let store = store.lock().unwrap();
+
+    let keychain = HdKeychain::load(&store)
+        .map_err(wallet_error)?
+        .ok_or_else(|| error::ErrorNotFound("HD wallet is not created yet"))?;
+
+    let account_key = keychain
+        .account_key()
+        .map_err(error::ErrorInternalServerError)?;
+    let addresses = (0..keychain.next_index)
+        .map(|index| {
+            HdKeychain::derive_wallet(&account_key, index).map(|wallet| wallet.generate_address())
+        })
+        .collect::<crate::utils::Result<Vec<Address>>>()
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(Json(HdWalletResponse {
+        mnemonic: None,
+        account_path: keychain.account_path,
+        next_index: keychain.next_index,
+        addresses,
+    }))
+}
+
+#[get("/wallet/keystore")]
+pub async fn get_keystore(state: Data<AppState>) -> Result<Json<KeystoreResponse>> {
+    let store = Arc::clone(&state.store);
+    let store = store.lock().unwrap();
+
+    keystore_response(&store)
+}
+
+#[post("/wallet/passphrase")]
+pub async fn change_passphrase(
+    state: Data<AppState>,
+    body: Json<PassphraseBody>,
+) -> Result<Json<KeystoreResponse>> {
+    if body.passphrase.is_empty() {
+        return Err(error::ErrorBadRequest("Passphrase can't be empty"));
+    }
+
+    let store = Arc::clone(&state.store);
+    let mut store = store.lock().unwrap();
+
+    Keystore::change_passphrase(&mut store, body.old_passphrase.as_deref(), &body.passphrase)
+        .map_err(wallet_error)?;
+
+    keystore_response(&store)
+}
+
+#[post("/wallet/unlock")]
+pub async fn unlock_wallet(
+    state: Data<AppState>,
+    body: Json<UnlockBody>,
+) -> Result<Json<KeystoreResponse>> {
+    let store = Arc::clone(&state.store);
+    let mut store = store.lock().unwrap();
+
+    Keystore::unlock(&mut store, &body.passphrase).map_err(wallet_error)?;
+
+    keystore_response(&store)
+}
+
+#[post("/wallet/lock")]
+pub async fn lock_wallet(state: Data<AppState>) -> Result<Json<KeystoreResponse>> {
+    let store = Arc::clone(&state.store);
+    let mut store = store.lock().unwrap();
+
+    Keystore::lock(&mut store);
+
+    keystore_response(&store)
+}
+
+#[post("/wallet/import")]
+pub async fn import_wallet(
+    state: Data<AppState>,
+    body: Json<ImportWalletBody>,
+) -> Result<Json<CreateWalletResponse>> {
+    let store = Arc::clone(&state.store);
+    let store = store.lock().unwrap();
+
+    let wallet = Wallet::import_private_key(&body.private_key).map_err(error::ErrorBadRequest)?;
+    let address = wallet.generate_address();
+
+    if Wallet::contains(&address, &store).map_err(error::ErrorInternalServerError)? {
+        return Err(error::ErrorConflict("Wallet already exists"));
+    }
+
+    let wallet_address = wallet.save(&store).map_err(wallet_error)?;
+
+    // The address can be spent from now, it is not watched only anymore
+    WatchOnly::remove(&address, &store).map_err(error::ErrorInternalServerError)?;
+
+    Ok(Json(CreateWalletResponse { wallet_address }))
+}
+
+#[get("/wallet/watch")]
+pub async fn get_watch_only(state: Data<AppState>) -> Result<Json<Vec<WatchOnlyResponse>>> {
+    let store = Arc::clone(&state.store);
+    let store = store.lock().unwrap();
+
+    watch_only_response(&store)
+}
+
+#[post("/wallet/watch")]
+pub async fn watch_address(
+    state: Data<AppState>,
+    body: Json<WatchOnlyBody>,
+) -> Result<Json<WatchOnlyResponse>> {
+    let store = Arc::clone(&state.store);
+    let store = store.lock().unwrap();
+
+    if Wallet::contains(&body.address, &store).map_err(error::ErrorInternalServerError)? {
+        return Err(error::ErrorConflict("Address belongs to a local wallet"));
+    }
+
+    WatchOnly::add(&body.address, &store).map_err(error::ErrorInternalServerError)?;
+
+    Ok(Json(WatchOnlyResponse {
+        address: body.address.clone(),
+        balance: balance_of(&store, body.address.pub_key_hash())?,
+    }))
+}
+
+#[delete("/wallet/watch/{address}")]
+pub async fn unwatch_address(
+    state: Data<AppState>,
+    path: Path<(String,)>,
+) -> Result<Json<Vec<WatchOnlyResponse>>> {
+    let store = Arc::clone(&state.store);
+    let store = store.lock().unwrap();
+
+    let address = parse_address(&path.into_inner().0)?;
+
+    if !WatchOnly::remove(&address, &store).map_err(error::ErrorInternalServerError)? {
+        return Err(error::ErrorNotFound("Address is not watched"));
+    }
+
+    watch_only_response(&store)
+}
+
+#[get("/wallet/{address}/export")]
+pub async fn export_wallet(
+    state: Data<AppState>,
+    path: Path<(String,)>,
+) -> Result<Json<ExportWalletResponse>> {
+    let store = Arc::clone(&state.store);
+    let store = store.lock().unwrap();
+
+    let address = parse_address(&path.into_inner().0)?;
+
+    let wallet = Wallet::get_by(&address, &store).map_err(wallet_error)?;
+
+    Ok(Json(ExportWalletResponse {
+        address,
+        private_key: wallet.export_private_key(),
+    }))
+}
+
+#[get("/wallet/{address}")]
+pub async fn get_wallet(
+    state: Data<AppState>,
+    path: Path<(String,)>,
+) -> Result<Json<WalletResponse>> {
+    let store = Arc::clone(&state.store);
+    let store = store.lock().unwrap();
+
+    let address = parse_address(&path.into_inner().0)?;
+
+    let wallet = Wallet::get_by(&address, &store).map_err(wallet_error)?;
+
+    Ok(Json(WalletResponse {
+        address,
+        pub_key: wallet.pub_key_bytes_vec().into(),
+    }))
 }
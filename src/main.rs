@@ -1,39 +1,131 @@
-use actix_web::web::Data;
-use actix_web::{App, HttpServer};
+use actix_web::web::{Data, JsonConfig};
+use actix_web::{error, App, HttpServer};
+use blockchain::address::Network;
+use blockchain::keystore::Keystore;
+use blockchain::miner::Miner;
+use blockchain::wallet::Wallet;
 use http::{
-    add_chain_block, create_blockchain, get_balance, get_blockchain, get_wallets, new_wallet,
+    cancel_mining, change_passphrase, claim_htlc, connect_peer, cosign_multisig, create_blockchain,
+    create_hd_wallet, drop_tx_index, export_wallet, get_balance, get_block_by_height,
+    get_blockchain, get_blocks_range, get_chain_tips, get_hd_wallet, get_history, get_keystore,
+    get_mempool, get_miner_status, get_peers, get_supply, get_transaction, get_wallet, get_wallets,
+    get_watch_only, import_wallet, lock_wallet, mine_block, new_htlc_secret, new_wallet,
+    publish_data, rebuild_tx_index, refund_htlc, rewind_chain, send_coins, send_htlc,
+    send_multisig, spend_multisig, submit_block, submit_transaction, unlock_wallet,
+    unwatch_address, watch_address,
 };
+use network::Node;
 use store::AppStore;
 
+use std::env;
 use std::io;
 use std::sync::{Arc, Mutex};
 
 mod blockchain;
 mod http;
+mod network;
 mod store;
 mod utils;
 
 pub struct AppState {
     store: Arc<Mutex<AppStore>>,
+    miner: Arc<Miner>,
+    node: Arc<Node>,
 }
 
 #[actix_web::main]
 async fn main() -> io::Result<()> {
-    let app_state = Data::new(AppState {
-        store: AppStore::new(),
-    });
+    let http_address = env::var("HTTP_ADDRESS").unwrap_or_else(|_| "127.0.0.1:8080".to_string());
+    let node_address = env::var("NODE_ADDRESS").unwrap_or_else(|_| "127.0.0.1:3000".to_string());
+    // Comma separated addresses of nodes to connect on start
+    let seeds: Vec<String> = env::var("NODE_PEERS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect();
+
+    let network = env::var("NETWORK")
+        .map(|v| v.parse::<Network>())
+        .unwrap_or(Ok(Network::default()))
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    Network::set(network);
+    println!("[!] Network: {}", network);
+
+    let store = AppStore::new();
+
+    let finished = Keystore::finish_pending_change(&store.lock().unwrap())
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    if finished {
+        println!("[!] Keystore: interrupted passphrase change is finished");
+    }
+
+    let moved = Wallet::migrate_addresses(&store.lock().unwrap())
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    if moved > 0 {
+        println!("[!] Wallet: moved {} addresses to {}", moved, network);
+    }
+
+    let miner = Arc::new(Miner::new(Miner::available_threads()));
+
+    let node = Node::start(node_address, seeds, Arc::clone(&store), Arc::clone(&miner))
+        .map_err(|e| io::Error::other(e.to_string()))?;
+
+    let app_state = Data::new(AppState { store, miner, node });
 
     HttpServer::new(move || {
         App::new()
             .app_data(app_state.clone())
+            // Bad request bodies, e.g. mistyped addresses, answer with the reason
+            .app_data(
+                JsonConfig::default()
+                    .error_handler(|err, _| error::ErrorBadRequest(err.to_string())),
+            )
             .service(get_blockchain)
-            .service(add_chain_block)
+            .service(get_block_by_height)
+            .service(get_blocks_range)
+            .service(submit_block)
+            .service(get_chain_tips)
+            .service(rewind_chain)
+            .service(send_coins)
+            .service(send_multisig)
+            .service(spend_multisig)
+            .service(cosign_multisig)
+            .service(publish_data)
+            .service(new_htlc_secret)
+            .service(send_htlc)
+            .service(claim_htlc)
+            .service(refund_htlc)
+            .service(submit_transaction)
+            .service(get_transaction)
+            .service(rebuild_tx_index)
+            .service(drop_tx_index)
+            .service(get_mempool)
+            .service(mine_block)
+            .service(get_miner_status)
+            .service(cancel_mining)
             .service(create_blockchain)
+            .service(get_history)
             .service(get_balance)
+            .service(get_supply)
             .service(new_wallet)
             .service(get_wallets)
+            .service(create_hd_wallet)
+            .service(get_hd_wallet)
+            .service(get_keystore)
+            .service(change_passphrase)
+            .service(unlock_wallet)
+            .service(lock_wallet)
+            .service(import_wallet)
+            .service(get_watch_only)
+            .service(watch_address)
+            .service(unwatch_address)
+            .service(export_wallet)
+            .service(get_wallet)
+            .service(get_peers)
+            .service(connect_peer)
     })
-    .bind("127.0.0.1:8080")?
+    .bind(http_address)?
     .run()
     .await
 }
@@ -0,0 +1,91 @@
+use std::io::{Read, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    blockchain::{
+        address::Network,
+        block::{Block, BlockHeader},
+        transaction::Transaction,
+    },
+    utils::{HashHex, Result},
+};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Upper bound of an encoded packet, protects from allocating garbage lengths
+const MAX_PACKET_SIZE: usize = 32 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum InvKind {
+    Block,
+    Tx,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", content = "payload", rename_all = "lowercase")]
+pub enum Message {
+    /// Opens the handshake, `height` is `None` for a node without a chain.
+    /// Peers of another network are refused.
+    Version {
+        version: u32,
+        network: Network,
+        height: Option<u64>,
+    },
+    Verack,
+    /// Asks for main chain headers after the first known locator hash
+    GetHeaders {
+        locator: Vec<HashHex>,
+    },
+    Headers {
+        headers: Vec<BlockHeader>,
+    },
+    /// Announces known blocks or transactions
+    Inv {
+        kind: InvKind,
+        items: Vec<HashHex>,
+    },
+    GetData {
+        kind: InvKind,
+        items: Vec<HashHex>,
+    },
+    Block(Block),
+    Tx(Transaction),
+}
+
+/// Message along with the listening address of its sender, which replies go to.
+/// The address has to be on the host the packet comes from.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Packet {
+    pub from: String,
+    pub message: Message,
+}
+
+impl Packet {
+    /// Writes big-endian length prefixed JSON
+    pub fn write_to(&self, stream: &mut impl Write) -> Result<()> {
+        let data = serde_json::to_vec(self)?;
+
+        stream.write_all(&(data.len() as u32).to_be_bytes())?;
+        stream.write_all(&data)?;
+        stream.flush()?;
+
+        Ok(())
+    }
+
+    pub fn read_from(stream: &mut impl Read) -> Result<Self> {
+        let mut length = [0_u8; 4];
+        stream.read_exact(&mut length)?;
+
+        let length = u32::from_be_bytes(length) as usize;
+        if length > MAX_PACKET_SIZE {
+            return Err(format!("Packet of {} bytes is too large", length).into());
+        }
+
+        let mut data = vec![0_u8; length];
+        stream.read_exact(&mut data)?;
+
+        Ok(serde_json::from_slice(&data)?)
+    }
+}
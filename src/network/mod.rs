@@ -0,0 +1,473 @@
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::{
+    blockchain::{
+        address::Network, block::Block, mempool::Mempool, miner::Miner, reorg::ChainUpdate,
+        transaction::Transaction, Blockchain,
+    },
+    store::AppStore,
+    utils::{HashHex, Result},
+};
+
+use self::message::{InvKind, Message, Packet, PROTOCOL_VERSION};
+
+pub(crate) mod message;
+pub(crate) mod sync;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Incoming connections handled at once, the ones above are dropped
+const MAX_INCOMING: usize = 64;
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Peer {
+    pub address: String,
+    /// Height announced in the peer `Version`, `None` until it is received
+    pub height: Option<u64>,
+    pub version_received: bool,
+    pub version_sent: bool,
+    pub verack_received: bool,
+}
+
+impl Peer {
+    pub fn is_handshaken(&self) -> bool {
+        self.version_received && self.verack_received
+    }
+}
+
+/// P2P side of the node. Every packet goes over its own TCP connection: incoming
+/// ones are handled each on its own thread, outgoing ones are sent in order by a
+/// single thread.
+pub struct Node {
+    pub address: String,
+    store: Arc<Mutex<AppStore>>,
+    miner: Arc<Miner>,
+    peers: Mutex<HashMap<String, Peer>>,
+    outbox: Sender<(String, Packet)>,
+    /// Incoming connections being handled now
+    incoming: AtomicUsize,
+    /// Blocks asked by `GetData` along with the peer and the time of asking, each is
+    /// taken only from the peer it was asked from
+    requested_blocks: Mutex<HashMap<HashHex, (String, Instant)>>,
+    /// Downloaded blocks waiting for their parent bodies, by block hash
+    pending_blocks: Mutex<HashMap<HashHex, Block>>,
+}
+
+impl Node {
+    /// Binds the listener and starts the P2P threads, then greets `seeds`
+    pub fn start(
+        address: String,
+        seeds: Vec<String>,
+        store: Arc<Mutex<AppStore>>,
+        miner: Arc<Miner>,
+    ) -> Result<Arc<Self>> {
+        let listener = TcpListener::bind(&address)?;
+        let (outbox, outgoing) = mpsc::channel();
+
+        let node = Arc::new(Node {
+            address,
+            store,
+            miner,
+            peers: Mutex::new(HashMap::new()),
+            outbox,
+            incoming: AtomicUsize::new(0),
+            requested_blocks: Mutex::new(HashMap::new()),
+            pending_blocks: Mutex::new(HashMap::new()),
+        });
+
+        let sender = Arc::clone(&node);
+        thread::spawn(move || sender.run_sender(outgoing));
+
+        let receiver = Arc::clone(&node);
+        thread::spawn(move || receiver.run_listener(listener));
+
+        println!("[!] Node: listening on {}", node.address);
+
+        for seed in seeds {
+            node.connect(&seed)?;
+        }
+
+        Ok(node)
+    }
+
+    /// Opens the handshake with `address`
+    pub fn connect(&self, address: &str) -> Result<()> {
+        if address == self.address {
+            return Err("Node can't connect to itself".into());
+        }
+
+        self.peers
+            .lock()
+            .unwrap()
+            .entry(address.to_string())
+            .or_insert_with(|| Peer {
+                address: address.to_string(),
+                ..Peer::default()
+            });
+
+        self.send_version(address)
+    }
+
+    pub fn peers(&self) -> Vec<Peer> {
+        self.peers.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Adds `block` to the chain and relays it to peers once it joins the main chain
+    pub fn accept_block(
+        &self,
+        blockchain: &mut Blockchain,
+        block: Block,
+        source: Option<&str>,
+    ) -> Result<ChainUpdate> {
+        let hash = block.hash.clone();
+        let update = blockchain.add_block(block)?;
+
+        let mempool = Mempool { blockchain };
+        mempool.apply_chain_update(&update)?;
+
+        if !update.connected.is_empty() {
+            // Running jobs are built on the previous tip now
+            self.miner.restart();
+
+            self.broadcast(
+                Message::Inv {
+                    kind: InvKind::Block,
+                    items: vec![hash],
+                },
+                source,
+            );
+        }
+
+        Ok(update)
+    }
+
+    /// Queues `tx` into the memory pool and relays it to peers
+    pub fn accept_transaction(
+        &self,
+        blockchain: &Blockchain,
+        tx: Transaction,
+        source: Option<&str>,
+    ) -> Result<()> {
+        let tx_id = tx.id.clone();
+
+        let mempool = Mempool { blockchain };
+        mempool.add(tx)?;
+
+        self.miner.restart();
+
+        self.broadcast(
+            Message::Inv {
+                kind: InvKind::Tx,
+                items: vec![tx_id],
+            },
+            source,
+        );
+
+        Ok(())
+    }
+
+    /// Sends `message` to every handshaken peer except `except`
+    pub fn broadcast(&self, message: Message, except: Option<&str>) {
+        let peers = self.peers.lock().unwrap();
+
+        for peer in peers.values() {
+            if peer.is_handshaken() && Some(peer.address.as_str()) != except {
+                self.send(&peer.address, message.clone());
+            }
+        }
+    }
+
+    fn send(&self, address: &str, message: Message) {
+        let packet = Packet {
+            from: self.address.clone(),
+            message,
+        };
+
+        if self.outbox.send((address.to_string(), packet)).is_err() {
+            println!("[!] Node: sender is stopped");
+        }
+    }
+
+    fn send_version(&self, address: &str) -> Result<()> {
+        let height = {
+            let store = self.store.lock().unwrap();
+
+            if Blockchain::exists(&store) {
+                Some(Blockchain::new(None, &store)?.height()?)
+            } else {
+                None
+            }
+        };
+
+        if let Some(peer) = self.peers.lock().unwrap().get_mut(address) {
+            peer.version_sent = true;
+        }
+
+        self.send(
+            address,
+            Message::Version {
+                version: PROTOCOL_VERSION,
+                network: Network::current(),
+                height,
+            },
+        );
+
+        Ok(())
+    }
+
+    fn run_sender(&self, outgoing: Receiver<(String, Packet)>) {
+        for (address, packet) in outgoing {
+            let result = Self::deliver(&address, &packet);
+
+            if let Err(e) = result {
+                println!("[!] Node: peer {} is unreachable: {}", address, e);
+                self.peers.lock().unwrap().remove(&address);
+            }
+        }
+    }
+
+    fn deliver(address: &str, packet: &Packet) -> Result<()> {
+        let socket_address = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or("Peer address is not resolved")?;
+
+        let mut stream = TcpStream::connect_timeout(&socket_address, CONNECT_TIMEOUT)?;
+        packet.write_to(&mut stream)?;
+
+        Ok(())
+    }
+
+    fn run_listener(self: Arc<Self>, listener: TcpListener) {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(v) => v,
+                Err(e) => {
+                    println!("[!] Node: connection error: {}", e);
+                    continue;
+                }
+            };
+
+            if self.incoming.fetch_add(1, Ordering::SeqCst) >= MAX_INCOMING {
+                self.incoming.fetch_sub(1, Ordering::SeqCst);
+                println!("[!] Node: too many incoming connections, one is dropped");
+                continue;
+            }
+
+            let node = Arc::clone(&self);
+            thread::spawn(move || {
+                if let Err(e) = node.receive(stream) {
+                    println!("[!] Node: packet handling error: {}", e);
+                }
+
+                node.incoming.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    }
+
+    fn receive(&self, mut stream: TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+
+        let source = stream.peer_addr()?;
+        let packet = Packet::read_from(&mut stream)?;
+
+        Self::check_sender(&packet.from, &source)?;
+
+        self.handle(packet)
+    }
+
+    /// Replies go to the address a packet declares, so it has to be on the host the
+    /// packet came from. Otherwise anyone could make the node talk to a third party.
+    fn check_sender(from: &str, source: &SocketAddr) -> Result<()> {
+        let is_same_host = from
+            .to_socket_addrs()?
+            .any(|address| address.ip() == source.ip());
+
+        if !is_same_host {
+            return Err(format!("Packet from {} claims to be from {}", source.ip(), from).into());
+        }
+
+        Ok(())
+    }
+
+    fn handle(&self, packet: Packet) -> Result<()> {
+        let Packet { from, message } = packet;
+
+        let is_known = self
+            .peers
+            .lock()
+            .unwrap()
+            .get(&from)
+            .map(|peer| peer.version_received)
+            .unwrap_or(false);
+
+        match message {
+            Message::Version {
+                version,
+                network,
+                height,
+            } => self.handle_version(&from, version, network, height),
+            // Answer to our version, it may come before the version of the peer
+            Message::Verack => {
+                if let Some(peer) = self.peers.lock().unwrap().get_mut(&from) {
+                    peer.verack_received = true;
+                }
+
+                Ok(())
+            }
+            _ if !is_known => Err(format!("Peer {} has not sent its version", from).into()),
+            Message::GetHeaders { locator } => self.handle_get_headers(&from, locator),
+            Message::Headers { headers } => self.handle_headers(&from, headers),
+            Message::Inv { kind, items } => self.handle_inv(&from, kind, items),
+            Message::GetData { kind, items } => self.handle_get_data(&from, kind, items),
+            Message::Block(block) => self.handle_block(&from, block),
+            Message::Tx(tx) => self.handle_tx(&from, tx),
+        }
+    }
+
+    fn handle_version(
+        &self,
+        from: &str,
+        version: u32,
+        network: Network,
+        height: Option<u64>,
+    ) -> Result<()> {
+        if version != PROTOCOL_VERSION {
+            return Err(format!("Peer {} uses protocol version {}", from, version).into());
+        }
+
+        // Blocks and addresses of another network are meaningless here
+        if network != Network::current() {
+            self.peers.lock().unwrap().remove(from);
+
+            return Err(format!("Peer {} runs on {}", from, network).into());
+        }
+
+        let version_sent = {
+            let mut peers = self.peers.lock().unwrap();
+
+            let peer = peers.entry(from.to_string()).or_insert_with(|| Peer {
+                address: from.to_string(),
+                ..Peer::default()
+            });
+            peer.version_received = true;
+            peer.height = height;
+
+            peer.version_sent
+        };
+
+        self.send(from, Message::Verack);
+
+        if !version_sent {
+            self.send_version(from)?;
+        }
+
+        let store = self.store.lock().unwrap();
+
+        let our_height = if Blockchain::exists(&store) {
+            Some(Blockchain::new(None, &store)?.height()?)
+        } else {
+            None
+        };
+
+        if height > our_height {
+            self.request_headers(from, &store)?;
+        }
+
+        Ok(())
+    }
+
+    fn handle_inv(&self, from: &str, kind: InvKind, items: Vec<HashHex>) -> Result<()> {
+        let store = self.store.lock().unwrap();
+        if !Blockchain::exists(&store) {
+            return Ok(());
+        }
+
+        let blockchain = Blockchain::new(None, &store)?;
+        let mempool = Mempool {
+            blockchain: &blockchain,
+        };
+
+        let mut missing = Vec::new();
+        for item in items.iter() {
+            let is_known = match kind {
+                InvKind::Block => blockchain.has_block(item)?,
+                InvKind::Tx => mempool.get(item)?.is_some(),
+            };
+
+            if !is_known {
+                missing.push(item.clone());
+            }
+        }
+
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        match kind {
+            InvKind::Block => self.request_blocks(from, missing),
+            InvKind::Tx => self.send(
+                from,
+                Message::GetData {
+                    kind,
+                    items: missing,
+                },
+            ),
+        }
+
+        Ok(())
+    }
+
+    fn handle_get_data(&self, from: &str, kind: InvKind, items: Vec<HashHex>) -> Result<()> {
+        let store = self.store.lock().unwrap();
+        if !Blockchain::exists(&store) {
+            return Ok(());
+        }
+
+        let blockchain = Blockchain::new(None, &store)?;
+        let mempool = Mempool {
+            blockchain: &blockchain,
+        };
+
+        for item in items.iter() {
+            match kind {
+                InvKind::Block => {
+                    if let Some(block) = blockchain.get_block(item)? {
+                        self.send(from, Message::Block(block));
+                    }
+                }
+                InvKind::Tx => {
+                    if let Some(tx) = mempool.get(item)? {
+                        self.send(from, Message::Tx(tx));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_tx(&self, from: &str, tx: Transaction) -> Result<()> {
+        let store = self.store.lock().unwrap();
+        if !Blockchain::exists(&store) {
+            return Ok(());
+        }
+
+        let blockchain = Blockchain::new(None, &store)?;
+
+        self.accept_transaction(&blockchain, tx, Some(from))
+    }
+}
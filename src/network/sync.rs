@@ -0,0 +1,301 @@
+use std::time::{Duration, Instant};
+
+use crate::{
+    blockchain::{
+        block::{Block, BlockHeader},
+        validation::ValidationError,
+        Blockchain, OrphanBlockError,
+    },
+    store::AppStore,
+    utils::{HashHex, Result},
+};
+
+use super::{
+    message::{InvKind, Message},
+    Node,
+};
+
+/// Most headers a single `Headers` answer carries
+const MAX_HEADERS: usize = 2000;
+
+/// Block bodies asked from a peer by one `GetData`
+const DOWNLOAD_CHUNK: usize = 16;
+
+/// Blocks asked and not received yet, requests older than `REQUEST_TIMEOUT`
+/// give way to new ones once there are that many
+const MAX_REQUESTED_BLOCKS: usize = 2 * MAX_HEADERS;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Downloaded blocks kept while their parents are on the way, the ones above are
+/// dropped and asked again with the next headers
+const MAX_PENDING_BLOCKS: usize = 512;
+
+/// Headers-first synchronization: the header chain is pulled and validated first,
+/// then block bodies are downloaded from all suitable peers at once.
+impl Node {
+    /// Asks `peer` for headers after our main chain, or from its genesis block
+    /// when we have no chain yet
+    pub(super) fn request_headers(&self, peer: &str, store: &AppStore) -> Result<()> {
+        let locator = if Blockchain::exists(store) {
+            Blockchain::new(None, store)?.block_locator()?
+        } else {
+            vec![]
+        };
+
+        self.send(peer, Message::GetHeaders { locator });
+
+        Ok(())
+    }
+
+    pub(super) fn handle_get_headers(&self, from: &str, locator: Vec<HashHex>) -> Result<()> {
+        let store = self.store.lock().unwrap();
+        if !Blockchain::exists(&store) {
+            return Ok(());
+        }
+
+        let blockchain = Blockchain::new(None, &store)?;
+        let headers = blockchain.headers_after(&locator, MAX_HEADERS)?;
+
+        if !headers.is_empty() {
+            self.send(from, Message::Headers { headers });
+        }
+
+        Ok(())
+    }
+
+    pub(super) fn handle_headers(&self, from: &str, headers: Vec<BlockHeader>) -> Result<()> {
+        let store = self.store.lock().unwrap();
+
+        let first = match headers.first() {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        // The genesis block is the only one which can't be validated by its header,
+        // it is downloaded first and the headers are asked again afterwards
+        if !Blockchain::exists(&store) {
+            if first.prev_hash.0.is_empty() {
+                self.request_blocks(from, vec![first.hash()]);
+            }
+
+            return Ok(());
+        }
+
+        let blockchain = Blockchain::new(None, &store)?;
+
+        let mut missing = Vec::new();
+        for header in headers.iter() {
+            let index = blockchain.store_header(header).inspect_err(|e| {
+                println!("[!] Node: header from {} is rejected: {}", from, e);
+            })?;
+
+            let hash = index.hash();
+            if !blockchain.has_block(&hash)? {
+                missing.push((hash, index.height));
+            }
+        }
+
+        println!(
+            "[!] Node: {} headers from {}, {} block bodies to download",
+            headers.len(),
+            from,
+            missing.len()
+        );
+
+        // A full answer means the peer has more headers after the last one
+        if headers.len() == MAX_HEADERS {
+            let last = headers.last().map(|header| header.hash());
+
+            self.send(
+                from,
+                Message::GetHeaders {
+                    locator: last.into_iter().collect(),
+                },
+            );
+        }
+
+        self.request_bodies(from, missing);
+
+        Ok(())
+    }
+
+    /// Spreads the download of `missing` (hash, height) blocks over the peers which
+    /// announced enough height, `source` of the headers always takes part
+    fn request_bodies(&self, source: &str, missing: Vec<(HashHex, u64)>) {
+        let pending = self.pending_blocks.lock().unwrap();
+        let missing: Vec<(HashHex, u64)> = missing
+            .into_iter()
+            .filter(|(hash, _)| !pending.contains_key(hash))
+            .collect();
+        drop(pending);
+
+        let peers = self.peers();
+
+        for (position, chunk) in missing.chunks(DOWNLOAD_CHUNK).enumerate() {
+            let top_height = chunk.last().map(|(_, height)| *height).unwrap_or(0);
+
+            let mut candidates: Vec<&str> = vec![source];
+            candidates.extend(
+                peers
+                    .iter()
+                    .filter(|peer| {
+                        peer.address != source
+                            && peer.is_handshaken()
+                            && peer.height >= Some(top_height)
+                    })
+                    .map(|peer| peer.address.as_str()),
+            );
+
+            let peer = candidates[position % candidates.len()];
+
+            self.request_blocks(peer, chunk.iter().map(|(hash, _)| hash.clone()).collect());
+        }
+    }
+
+    /// Asks `peer` for the bodies of `hashes` and remembers them, so the answer is taken
+    pub(super) fn request_blocks(&self, peer: &str, hashes: Vec<HashHex>) {
+        let mut requested = self.requested_blocks.lock().unwrap();
+
+        if requested.len() + hashes.len() > MAX_REQUESTED_BLOCKS {
+            requested.retain(|_, (_, asked_at)| asked_at.elapsed() < REQUEST_TIMEOUT);
+        }
+
+        let now = Instant::now();
+        let mut items = Vec::new();
+        for hash in hashes {
+            if requested.len() >= MAX_REQUESTED_BLOCKS && !requested.contains_key(&hash) {
+                break;
+            }
+
+            requested.insert(hash.clone(), (peer.to_string(), now));
+            items.push(hash);
+        }
+        drop(requested);
+
+        if items.is_empty() {
+            println!("[!] Node: too many blocks are requested already");
+            return;
+        }
+
+        self.send(
+            peer,
+            Message::GetData {
+                kind: InvKind::Block,
+                items,
+            },
+        );
+    }
+
+    pub(super) fn handle_block(&self, from: &str, block: Block) -> Result<()> {
+        // Only the body asked from this peer is taken, and it has to hash to the header
+        // it is for
+        let is_requested = {
+            let mut requested = self.requested_blocks.lock().unwrap();
+
+            match requested.get(&block.hash) {
+                Some((peer, _)) if peer == from => requested.remove(&block.hash).is_some(),
+                _ => false,
+            }
+        };
+        if !is_requested {
+            return Err(format!(
+                "Block {} was not requested from {}",
+                hex::encode(&block.hash.0),
+                from
+            )
+            .into());
+        }
+
+        if block.header.hash() != block.hash {
+            return Err(ValidationError::BadHash.into());
+        }
+
+        let store = self.store.lock().unwrap();
+
+        if !Blockchain::exists(&store) {
+            if !block.header.prev_hash.0.is_empty() {
+                return Ok(());
+            }
+
+            Blockchain::from_genesis(block, &store)?;
+            println!("[!] Node: genesis block is received from {}", from);
+
+            return self.request_headers(from, &store);
+        }
+
+        let mut blockchain = Blockchain::new(None, &store)?;
+
+        let hash = block.hash.clone();
+
+        // Several peers may answer the same request
+        if blockchain.has_block(&hash)? {
+            return Ok(());
+        }
+
+        let is_header_known = blockchain.get_block_index(&hash)?.is_some();
+
+        match self.accept_block(&mut blockchain, block.clone(), Some(from)) {
+            Ok(_) => {}
+            Err(e) if e.is::<OrphanBlockError>() => {
+                if is_header_known {
+                    // Bodies may arrive out of order, the parent one is on its way
+                    let mut pending = self.pending_blocks.lock().unwrap();
+
+                    if pending.len() < MAX_PENDING_BLOCKS {
+                        pending.insert(hash, block);
+                    } else {
+                        println!(
+                            "[!] Node: too many blocks wait for parents, {} is dropped",
+                            hex::encode(&hash.0)
+                        );
+                    }
+                } else {
+                    // Blocks between our chain and the received one are missing
+                    self.request_headers(from, &store)?;
+                }
+
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        }
+
+        self.connect_pending(&mut blockchain, hash, from);
+
+        Ok(())
+    }
+
+    /// Adds waiting descendants of the just stored block `parent`
+    fn connect_pending(&self, blockchain: &mut Blockchain, parent: HashHex, from: &str) {
+        let mut parents = vec![parent];
+
+        while let Some(parent) = parents.pop() {
+            let children: Vec<Block> = {
+                let mut pending = self.pending_blocks.lock().unwrap();
+
+                let hashes: Vec<HashHex> = pending
+                    .values()
+                    .filter(|block| block.header.prev_hash == parent)
+                    .map(|block| block.hash.clone())
+                    .collect();
+
+                hashes
+                    .iter()
+                    .filter_map(|hash| pending.remove(hash))
+                    .collect()
+            };
+
+            for child in children {
+                let hash = child.hash.clone();
+
+                match self.accept_block(blockchain, child, Some(from)) {
+                    Ok(_) => parents.push(hash),
+                    Err(e) => println!(
+                        "[!] Node: block {} is rejected: {}",
+                        hex::encode(&hash.0),
+                        e
+                    ),
+                }
+            }
+        }
+    }
+}
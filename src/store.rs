@@ -1,7 +1,16 @@
-use crate::{blockchain::transaction::TXOutput, utils::Result};
+use crate::{
+    blockchain::{
+        hd_wallet::HdKeychain,
+        keystore::{KeystoreParams, WalletKey},
+        transaction::{TXOutput, Transaction},
+        tx_index::TxLocation,
+        utxo_set::BlockUndo,
+    },
+    utils::Result,
+};
 use std::{
-    fs::File,
-    sync::{Arc, Mutex}, collections::HashMap,
+    collections::HashMap,
+    sync::{Arc, Mutex},
 };
 
 use kv::{Bucket, Config, Json, Raw, Store};
@@ -9,44 +18,84 @@ use kv::{Bucket, Config, Json, Raw, Store};
 pub const DB_PATH: &str = "./store";
 
 pub const BLOCKS_BUCKET: &str = "blocks";
+pub const HEADERS_BUCKET: &str = "headers";
+pub const HEIGHTS_BUCKET: &str = "heights";
 pub const CHAINSTATE_BUCKET: &str = "chainstate";
 pub const WALLETS_BUCKET: &str = "wallets";
-
-pub struct AppStore(pub Store);
+pub const HD_WALLET_BUCKET: &str = "hd_wallet";
+pub const KEYSTORE_BUCKET: &str = "keystore";
+pub const KEYSTORE_PENDING_BUCKET: &str = "keystore_pending";
+pub const WATCH_ONLY_BUCKET: &str = "watch_only";
+pub const MEMPOOL_BUCKET: &str = "mempool";
+pub const TXINDEX_BUCKET: &str = "txindex";
+pub const TXINDEX_BUILD_BUCKET: &str = "txindex_build";
+pub const UNDO_BUCKET: &str = "undo";
+pub const INVALID_BUCKET: &str = "invalid";
+
+/// Store along with the wallet key while the wallet is unlocked
+pub struct AppStore(pub Store, Option<WalletKey>);
 
 type TxId = Vec<u8>;
 type WalletAddress = Vec<u8>;
 type SecretKey = Vec<u8>;
+type PubKeyHash = Vec<u8>;
 type BlockHash = Vec<u8>;
-type BlockJson = Raw;
+type BlockRaw = Raw;
+type HeaderRaw = Raw;
+type BlockHeight = Vec<u8>;
+type TxOutputsJson = Json<HashMap<i32, TXOutput>>;
+type TransactionJson = Json<Transaction>;
+type TxLocationJson = Json<TxLocation>;
+type BlockUndoJson = Json<BlockUndo>;
+type HdKeychainJson = Json<HdKeychain>;
+type KeystoreParamsJson = Json<KeystoreParams>;
 
 impl<'a> AppStore {
     pub fn new() -> Arc<Mutex<Self>> {
-        let cfg: Config;
-        if File::open(DB_PATH).is_ok() {
-            cfg = Config::load(DB_PATH).unwrap();
-        } else {
-            cfg = Config::new(DB_PATH);
-        }
+        // Store directory is reopened as is, it keeps no kv config file
+        let cfg = Config::new(DB_PATH);
 
         let store = Store::new(cfg).unwrap();
 
-        Arc::new(Mutex::new(AppStore(store)))
+        Arc::new(Mutex::new(AppStore(store, None)))
     }
 
     pub fn get_blocks_bucket(&self) -> Result<Bucket<'a, Vec<u8>, Raw>> {
         let store = &self.0;
 
-        let bucket = store.bucket::<BlockHash, BlockJson>(Some(BLOCKS_BUCKET)).unwrap();
+        let bucket = store
+            .bucket::<BlockHash, BlockRaw>(Some(BLOCKS_BUCKET))
+            .unwrap();
+
+        Ok(bucket)
+    }
+
+    pub fn get_headers_bucket(&self) -> Result<Bucket<'a, Vec<u8>, Raw>> {
+        let store = &self.0;
+
+        let bucket = store
+            .bucket::<BlockHash, HeaderRaw>(Some(HEADERS_BUCKET))
+            .unwrap();
+
+        Ok(bucket)
+    }
+
+    /// Main chain height (big-endian, so keys are ordered) -> block hash
+    pub fn get_heights_bucket(&self) -> Result<Bucket<'a, Vec<u8>, Vec<u8>>> {
+        let store = &self.0;
+
+        let bucket = store
+            .bucket::<BlockHeight, BlockHash>(Some(HEIGHTS_BUCKET))
+            .unwrap();
 
         Ok(bucket)
     }
 
-    pub fn get_chainstate_bucket(&self) -> Result<Bucket<'a, Vec<u8>, Json<HashMap<i32, TXOutput>>>> {
+    pub fn get_chainstate_bucket(&self) -> Result<Bucket<'a, Vec<u8>, TxOutputsJson>> {
         let store = &self.0;
 
         let bucket = store
-            .bucket::<TxId, Json<HashMap<i32, TXOutput>>>(Some(CHAINSTATE_BUCKET))
+            .bucket::<TxId, TxOutputsJson>(Some(CHAINSTATE_BUCKET))
             .unwrap();
 
         Ok(bucket)
@@ -61,4 +110,132 @@ impl<'a> AppStore {
 
         Ok(bucket)
     }
+
+    pub fn get_watch_only_bucket(&self) -> Result<Bucket<'a, Vec<u8>, Vec<u8>>> {
+        let store = &self.0;
+
+        let bucket = store
+            .bucket::<WalletAddress, PubKeyHash>(Some(WATCH_ONLY_BUCKET))
+            .unwrap();
+
+        Ok(bucket)
+    }
+
+    /// Mnemonic seed which local wallets are derived from, see `HdKeychain`
+    pub fn get_hd_wallet_bucket(&self) -> Result<Bucket<'a, Vec<u8>, HdKeychainJson>> {
+        let store = &self.0;
+
+        let bucket = store
+            .bucket::<Vec<u8>, HdKeychainJson>(Some(HD_WALLET_BUCKET))
+            .unwrap();
+
+        Ok(bucket)
+    }
+
+    /// Salt and passphrase check of encrypted wallets, see `Keystore`
+    pub fn get_keystore_bucket(&self) -> Result<Bucket<'a, Vec<u8>, KeystoreParamsJson>> {
+        let store = &self.0;
+
+        let bucket = store
+            .bucket::<Vec<u8>, KeystoreParamsJson>(Some(KEYSTORE_BUCKET))
+            .unwrap();
+
+        Ok(bucket)
+    }
+
+    /// Secrets of a passphrase change staged before they are written, see
+    /// `Keystore::finish_pending_change`
+    pub fn get_keystore_pending_bucket(&self) -> Result<Bucket<'a, Vec<u8>, Vec<u8>>> {
+        let store = &self.0;
+
+        let bucket = store
+            .bucket::<Vec<u8>, Vec<u8>>(Some(KEYSTORE_PENDING_BUCKET))
+            .unwrap();
+
+        Ok(bucket)
+    }
+
+    pub fn get_mempool_bucket(&self) -> Result<Bucket<'a, Vec<u8>, TransactionJson>> {
+        let store = &self.0;
+
+        let bucket = store
+            .bucket::<TxId, TransactionJson>(Some(MEMPOOL_BUCKET))
+            .unwrap();
+
+        Ok(bucket)
+    }
+
+    pub fn get_txindex_bucket(&self) -> Result<Bucket<'a, Vec<u8>, TxLocationJson>> {
+        let store = &self.0;
+
+        let bucket = store
+            .bucket::<TxId, TxLocationJson>(Some(TXINDEX_BUCKET))
+            .unwrap();
+
+        Ok(bucket)
+    }
+
+    /// Block hash -> outputs spent by the block, see `UTXOSet::disconnect`
+    pub fn get_undo_bucket(&self) -> Result<Bucket<'a, Vec<u8>, BlockUndoJson>> {
+        let store = &self.0;
+
+        let bucket = store
+            .bucket::<BlockHash, BlockUndoJson>(Some(UNDO_BUCKET))
+            .unwrap();
+
+        Ok(bucket)
+    }
+
+    /// Hashes of blocks which failed validation, see `Blockchain::invalidate_block`
+    pub fn get_invalid_bucket(&self) -> Result<Bucket<'a, Vec<u8>, Vec<u8>>> {
+        let store = &self.0;
+
+        let bucket = store
+            .bucket::<BlockHash, Vec<u8>>(Some(INVALID_BUCKET))
+            .unwrap();
+
+        Ok(bucket)
+    }
+
+    pub fn wallet_key(&self) -> Option<&WalletKey> {
+        self.1.as_ref()
+    }
+
+    pub fn set_wallet_key(&mut self, key: Option<WalletKey>) {
+        self.1 = key;
+    }
+
+    /// The index counts only once built in full, an unfinished build leaves its marker behind
+    pub fn has_txindex(&self) -> bool {
+        let buckets = self.0.buckets();
+
+        buckets.contains(&TXINDEX_BUCKET.to_string())
+            && !buckets.contains(&TXINDEX_BUILD_BUCKET.to_string())
+    }
+
+    /// Marks the index as being built, it is disabled until `finish_txindex_build`
+    pub fn start_txindex_build(&self) -> Result<()> {
+        self.0
+            .bucket::<Vec<u8>, Vec<u8>>(Some(TXINDEX_BUILD_BUCKET))?;
+
+        Ok(())
+    }
+
+    pub fn finish_txindex_build(&self) -> Result<()> {
+        self.0.drop_bucket(TXINDEX_BUILD_BUCKET)?;
+
+        Ok(())
+    }
+
+    pub fn drop_txindex(&self) -> Result<()> {
+        let buckets = self.0.buckets();
+
+        for name in [TXINDEX_BUCKET, TXINDEX_BUILD_BUCKET] {
+            if buckets.contains(&name.to_string()) {
+                self.0.drop_bucket(name)?;
+            }
+        }
+
+        Ok(())
+    }
 }
@@ -3,12 +3,11 @@ use std::time::{SystemTime, UNIX_EPOCH};
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 
-pub fn get_current_time() -> String {
+pub fn get_current_time() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .expect("Time went backwards")
-        .as_millis()
-        .to_string()
+        .as_millis() as u64
 }
 
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;